@@ -0,0 +1,152 @@
+//! Counts heap allocations made while loading a TypeScript grammar and
+//! tokenizing a large TypeScript file with it, and the bytes the loaded grammar
+//! holds on to.
+//!
+//! Needs the `tests/textmate-grammars-themes` submodule, or a path to a
+//! TypeScript grammar in `TYPESCRIPT_GRAMMAR`; without either, only loading a
+//! generated grammar is measured. Run with `cargo bench --bench allocations`.
+
+use rust_textmate::{Grammar, TokenizerState};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+// bytes allocated and not freed yet
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        HELD.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HELD.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const GRAMMAR_PATH: &str =
+    "tests/textmate-grammars-themes/packages/tm-grammars/grammars/typescript.json";
+
+const SOURCE: &str = r#"import { readFile } from "node:fs/promises";
+
+/** A parsed entry of the config file. */
+export interface Entry<T = string> {
+    readonly name: string;
+    values: Array<T>;
+}
+
+export async function load(path: string): Promise<Entry[]> {
+    const text = await readFile(path, "utf8");
+    // one entry per non-empty line, `name = a, b, c`
+    return text
+        .split(/\r?\n/)
+        .filter((line) => line.trim().length > 0)
+        .map((line, index) => {
+            const [name, rest = ""] = line.split("=", 2);
+            return { name: name.trim(), values: rest.split(",").map((v) => v.trim()) };
+        });
+}
+"#;
+
+// A grammar shaped like real ones, for when there is no TypeScript grammar:
+// `keys` repository rules, each a begin/end with captures, two match patterns
+// and an include of the next one.
+fn generated_grammar(keys: usize) -> String {
+    let repository: Vec<_> = (0..keys)
+        .map(|i| {
+            let next = (i + 1) % keys;
+            format!(
+                r##""k{i}": {{
+                  "begin": "(kw{i})\\s*(\\()",
+                  "end": "\\)",
+                  "name": "meta.block.k{i}",
+                  "beginCaptures": {{
+                    "1": {{ "name": "keyword.k{i}" }},
+                    "2": {{ "name": "punctuation.open" }}
+                  }},
+                  "endCaptures": {{ "0": {{ "name": "punctuation.close" }} }},
+                  "patterns": [
+                    {{ "match": "\\b(word{i})\\b", "name": "variable.k{i}" }},
+                    {{ "match": "\"[^\"]*{i}\"", "name": "string.k{i}" }},
+                    {{ "include": "#k{next}" }}
+                  ]
+                }}"##
+            )
+        })
+        .collect();
+    let patterns: Vec<_> = (0..keys)
+        .map(|i| format!(r##"{{ "include": "#k{i}" }}"##))
+        .collect();
+    format!(
+        r#"{{ "scopeName": "source.generated", "patterns": [{}], "repository": {{ {} }} }}"#,
+        patterns.join(","),
+        repository.join(",")
+    )
+}
+
+fn load(json: &str) -> Grammar {
+    let (allocations, held) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        HELD.load(Ordering::Relaxed),
+    );
+    let grammar = Grammar::from_json(json).unwrap();
+    println!(
+        "loading: {} allocations, {} bytes held",
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        HELD.load(Ordering::Relaxed) - held
+    );
+    grammar
+}
+
+fn count_allocations(label: &str, lines: &[&str], mut tokenize: impl FnMut(&str)) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for line in lines {
+        tokenize(line);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{label}: {allocations} allocations, {:.1} per line",
+        allocations as f64 / lines.len() as f64
+    );
+}
+
+fn main() {
+    let path = std::env::var("TYPESCRIPT_GRAMMAR")
+        .unwrap_or_else(|_| format!("{}/{GRAMMAR_PATH}", env!("CARGO_MANIFEST_DIR")));
+    let Ok(json) = std::fs::read_to_string(&path) else {
+        eprintln!("{path} is missing, is the submodule checked out? Loading a generated grammar");
+        load(&generated_grammar(2000));
+        return;
+    };
+    let grammar = load(&json);
+
+    let source = SOURCE.repeat(500);
+    let lines: Vec<&str> = source.lines().collect();
+
+    // compiles the regexes up front, so that only tokenization is counted
+    let mut state = TokenizerState::default();
+    for line in SOURCE.lines() {
+        grammar.tokenize_line(line, &mut state);
+    }
+
+    let mut state = TokenizerState::default();
+    count_allocations("string scopes", &lines, |line| {
+        grammar.tokenize_line(line, &mut state);
+    });
+    let mut state = TokenizerState::default();
+    count_allocations("borrowed scopes", &lines, |line| {
+        grammar.tokenize_to_scopes(line, &mut state);
+    });
+    let mut state = TokenizerState::default();
+    count_allocations("interned scopes", &lines, |line| {
+        grammar.tokenize_to_ids(line, &mut state);
+    });
+}
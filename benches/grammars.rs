@@ -0,0 +1,83 @@
+//! Per-grammar timings of parsing, compiling and tokenizing the grammars of the
+//! `tests/textmate-grammars-themes` submodule. Run with `cargo bench --bench grammars`,
+//! optionally with a filter such as `cargo bench --bench grammars -- rust`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use rust_textmate::{Grammar, stages};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const GRAMMARS_PATH: &str = "tests/textmate-grammars-themes/packages/tm-grammars/grammars";
+// a `<name>.sample` file per grammar, where there is one
+const SAMPLES_PATH: &str = "tests/textmate-grammars-themes/packages/tm-grammars/samples";
+
+// the same grammars the compiler tests skip
+const PROBLEMATIC_GRAMMARS: &[&str] = &[
+    "wikitext.json",
+    "stata.json",
+    "racket.json",
+    "xml.json",
+    "smalltalk.json",
+];
+
+fn grammar_paths() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(GRAMMARS_PATH);
+    let Ok(entries) = dir.read_dir() else {
+        eprintln!(
+            "no grammars in {}; is the submodule checked out?",
+            dir.display()
+        );
+        return Vec::new();
+    };
+
+    let mut paths: Vec<_> = entries
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !PROBLEMATIC_GRAMMARS.iter().any(|&g| path.ends_with(g)))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn bench_grammars(c: &mut Criterion) {
+    let mut group = c.benchmark_group("grammars");
+    group
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(200))
+        .measurement_time(Duration::from_secs(1));
+
+    for path in grammar_paths() {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let json = std::fs::read_to_string(&path).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("parse", &name), &json, |b, json| {
+            b.iter(|| stages::parse(json).unwrap())
+        });
+
+        let parsed = stages::parse(&json).unwrap();
+        group.bench_with_input(BenchmarkId::new("compile", &name), &parsed, |b, parsed| {
+            b.iter_batched(
+                || parsed.clone(),
+                |parsed| stages::compile(parsed).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+
+        let sample_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(SAMPLES_PATH)
+            .join(format!("{name}.sample"));
+        let Ok(sample) = std::fs::read_to_string(sample_path) else {
+            continue;
+        };
+        let grammar = Grammar::from_json(&json).unwrap();
+        // regexes are compiled on first use; keep that out of the measurement
+        grammar.tokenize_file(&sample);
+        group.bench_with_input(BenchmarkId::new("tokenize", &name), &sample, |b, sample| {
+            b.iter(|| grammar.tokenize_file(sample))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_grammars);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+//! Timings of `Grammar::tokenize_file_parallel` against `Grammar::tokenize_file`
+//! on a generated C-like text, and of tokenizing every line to scope ids only,
+//! which is what a sequential pass to find where to split the text would cost.
+//! Run with `cargo bench --features rayon --bench parallel`.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rust_textmate::{Grammar, TokenizerState};
+
+const GRAMMAR: &str = r#"{
+  "scopeName": "source.c-like",
+  "patterns": [
+    { "begin": "/\\*", "end": "\\*/", "name": "comment.block" },
+    { "match": "//.*$", "name": "comment.line" },
+    { "begin": "\"", "end": "\"", "name": "string.quoted", "patterns": [{ "match": "\\\\.", "name": "constant.character.escape" }] },
+    { "match": "\\b(let|if|else|return|fn)\\b", "name": "keyword.control" },
+    { "match": "\\b[0-9]+\\b", "name": "constant.numeric" },
+    { "match": "\\b[a-z_][a-z0-9_]*(?=\\()", "name": "entity.name.function" },
+    { "match": "[-+*/=<>!]+", "name": "keyword.operator" }
+  ]
+}"#;
+
+const LINES: usize = 20_000;
+
+// lines that are independent of each other but for a short block comment every
+// 50 lines
+fn mostly_independent() -> String {
+    let mut text = String::new();
+    for idx in 0..LINES {
+        if idx % 50 == 0 {
+            text.push_str("/* a comment\n   over two lines */\n");
+        } else {
+            text.push_str(&format!(
+                "let x{idx} = call(\"str\\n\", {idx}) + {idx}; // note\n"
+            ));
+        }
+    }
+    text
+}
+
+// the worst case, everything after the first line is one comment
+fn one_comment() -> String {
+    format!("/*\n{}*/\n", mostly_independent().replace("*/", "* /"))
+}
+
+fn bench_parallel(c: &mut Criterion) {
+    let grammar = Grammar::from_json(GRAMMAR).unwrap();
+    let mut group = c.benchmark_group("parallel");
+    group.sample_size(10);
+
+    for (name, text) in [
+        ("mostly_independent", mostly_independent()),
+        ("one_comment", one_comment()),
+    ] {
+        group.bench_with_input(BenchmarkId::new("sequential", name), &text, |b, text| {
+            b.iter(|| grammar.tokenize_file(text))
+        });
+        group.bench_with_input(BenchmarkId::new("scan", name), &text, |b, text| {
+            b.iter(|| {
+                let mut state = TokenizerState::default();
+                for line in text.split('\n') {
+                    grammar.tokenize_to_ids(&format!("{line}\n"), &mut state);
+                }
+            })
+        });
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let id = BenchmarkId::new(format!("parallel_{threads}_threads"), name);
+            group.bench_with_input(id, &text, |b, text| {
+                b.iter(|| pool.install(|| grammar.tokenize_file_parallel(text)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parallel);
+criterion_main!(benches);
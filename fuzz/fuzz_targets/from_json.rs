@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_textmate::{Grammar, TokenizerState};
+
+// Loading a grammar parses, compiles and links it; none of that may panic,
+// whatever the input. Grammars that load are also used on a line, so that
+// their regexes get compiled.
+fuzz_target!(|json: &str| {
+    let Ok(grammar) = Grammar::from_json(json) else {
+        return;
+    };
+    grammar.tokenize_line("fuzz (a) \"b\" 1.0\n", &mut TokenizerState::default());
+});
@@ -0,0 +1,142 @@
+//! Highlights a file in the terminal.
+//!
+//! ```text
+//! tmhl [--grammar <file>] [--grammars <dir>] --theme <file> [--256] <input>
+//! ```
+//!
+//! Without `--grammar`, the grammar is picked from the grammars in `--grammars`
+//! by the input's extension, or else by its first line. Grammars can be JSON
+//! (with comments), XML plists (`.tmLanguage`, `.plist`) or YAML.
+
+use rust_textmate::{Grammar, SyntaxSet, SyntaxSetBuilder, Theme, render};
+use snafu::{OptionExt, ResultExt, Whatever, whatever};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str =
+    "usage: tmhl [--grammar <file>] [--grammars <dir>] --theme <file> [--256] <input>";
+
+#[derive(Default)]
+struct Args {
+    grammar: Option<PathBuf>,
+    grammars: Option<PathBuf>,
+    theme: Option<PathBuf>,
+    ansi_256: bool,
+    input: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, Whatever> {
+    let mut args = Args::default();
+    let mut raw = std::env::args_os().skip(1);
+    while let Some(arg) = raw.next() {
+        let mut value = || raw.next().map(PathBuf::from).whatever_context(USAGE);
+        match arg.to_str() {
+            Some("--grammar") => args.grammar = Some(value()?),
+            Some("--grammars") => args.grammars = Some(value()?),
+            Some("--theme") => args.theme = Some(value()?),
+            Some("--256") => args.ansi_256 = true,
+            Some(flag) if flag.starts_with("--") => whatever!("unknown option {flag}\n{USAGE}"),
+            _ if args.input.is_none() => args.input = Some(arg.into()),
+            _ => whatever!("{USAGE}"),
+        }
+    }
+    Ok(args)
+}
+
+fn read(path: &Path) -> Result<String, Whatever> {
+    fs::read_to_string(path).with_whatever_context(|_| format!("failed to read {}", path.display()))
+}
+
+// the format is told by the extension; JSON is read leniently, with comments
+fn add_grammar(builder: &mut SyntaxSetBuilder, path: &Path) -> Result<(), Whatever> {
+    let text = read(path)?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let added = match extension {
+        Some("tmLanguage" | "plist") => builder.add_from_plist(&text),
+        Some("yaml" | "yml") => builder.add_from_yaml(&text),
+        _ => builder.add_from_jsonc(&text),
+    };
+    added.with_whatever_context(|_| format!("failed to load grammar {}", path.display()))
+}
+
+fn is_grammar(path: &Path) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    matches!(
+        extension,
+        Some("json" | "tmLanguage" | "plist" | "yaml" | "yml")
+    )
+}
+
+fn load_grammars(args: &Args) -> Result<SyntaxSet, Whatever> {
+    let mut builder = SyntaxSetBuilder::new();
+    if let Some(grammar) = &args.grammar {
+        add_grammar(&mut builder, grammar)?;
+    }
+    if let Some(dir) = &args.grammars {
+        let entries = fs::read_dir(dir)
+            .with_whatever_context(|_| format!("failed to read {}", dir.display()))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry =
+                entry.with_whatever_context(|_| format!("failed to read {}", dir.display()))?;
+            paths.push(entry.path());
+        }
+        // grammars added first win ties between extensions, so keep it stable
+        paths.sort();
+        for path in paths.iter().filter(|path| is_grammar(path)) {
+            add_grammar(&mut builder, path)?;
+        }
+    }
+    builder
+        .build()
+        .whatever_context("failed to link the grammars")
+}
+
+fn pick_grammar(args: &Args, syntax_set: &SyntaxSet, input: &Path, text: &str) -> Option<Grammar> {
+    if args.grammar.is_some() {
+        return syntax_set.grammars().next();
+    }
+    let by_extension = input
+        .extension()
+        .or(input.file_name())
+        .and_then(|extension| syntax_set.find_by_extension(extension.to_str()?));
+    by_extension.or_else(|| syntax_set.find_by_first_line(text.lines().next()?))
+}
+
+#[snafu::report]
+fn main() -> Result<(), Whatever> {
+    let args = parse_args()?;
+    let input = args.input.as_deref().whatever_context(USAGE)?;
+    if args.grammar.is_none() && args.grammars.is_none() {
+        whatever!("either --grammar or --grammars is needed\n{USAGE}");
+    }
+    let theme_path = args.theme.as_deref().whatever_context(USAGE)?;
+
+    let theme_dir = theme_path.parent().unwrap_or(Path::new("."));
+    let theme = Theme::from_json_with_loader(&read(theme_path)?, |include, including| {
+        // relative to the theme that includes it
+        let mut dir = theme_dir.to_path_buf();
+        for theme in including {
+            dir = dir.join(theme).parent().unwrap_or(&dir).to_path_buf();
+        }
+        fs::read_to_string(dir.join(include))
+    })
+    .with_whatever_context(|_| format!("failed to load theme {}", theme_path.display()))?;
+
+    let syntax_set = load_grammars(&args)?;
+    let text = read(input)?;
+    let grammar = pick_grammar(&args, &syntax_set, input, &text)
+        .with_whatever_context(|| format!("no grammar for {}", input.display()))?;
+
+    // matches lines with their terminating `\n`, like editors do; `lines` drops
+    // the empty line after the last `\n`
+    for (tokens, line) in grammar.tokenize_iter(&text).zip(text.lines()) {
+        let highlighted = if args.ansi_256 {
+            render::to_ansi_256(&tokens, &theme, line)
+        } else {
+            render::to_ansi(&tokens, &theme, line)
+        };
+        println!("{highlighted}");
+    }
+    Ok(())
+}
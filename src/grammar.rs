@@ -0,0 +1,1705 @@
+use crate::syntax_compiler::compile::{CompileStats, RuleId, ScopeId};
+use crate::syntax_compiler::engine::RegexEngine;
+use crate::syntax_compiler::validate::ValidationWarning;
+use crate::syntax_compiler::{compile, parse};
+use crate::tokenizer::{
+    OffsetMode, Position, RegionChange, ScopeEvent, ScopeStack, Token, Tokenizer, TokenizerConfig,
+    TokenizerState,
+};
+use serde_derive::{Deserialize, Serialize};
+use snafu::prelude::*;
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// An error that occurred while loading a grammar.
+#[derive(Debug, Snafu)]
+pub struct Error(InnerError);
+
+#[derive(Debug, Snafu)]
+enum InnerError {
+    #[snafu(display("failed to parse grammar"))]
+    Parse { source: parse::Error },
+    #[snafu(display("failed to compile grammar"))]
+    Compile { source: compile::Error },
+    #[snafu(display("failed to link grammars"))]
+    Link { source: compile::Error },
+    #[snafu(display("no grammar with the scope name \"{}\" to patch", scope_name))]
+    PatchTarget { scope_name: String },
+    #[snafu(display("failed to patch grammar \"{}\"", scope_name))]
+    Patch {
+        scope_name: String,
+        source: compile::Error,
+    },
+    #[snafu(display("grammar \"{}\" has an invalid regex", scope_name))]
+    Precompile {
+        scope_name: String,
+        source: compile::Error,
+    },
+}
+
+/// A compiled TextMate grammar, ready for tokenization.
+///
+/// Grammars can be serialized with serde to skip compilation next time they are
+/// loaded. Regexes are stored as patterns and handed to the regex engine again
+/// on first use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grammar {
+    // shared with the other grammars of the set this one was loaded with
+    syntax_set: Arc<compile::SyntaxSet>,
+    syntax_id: compile::SyntaxId,
+    #[serde(skip)]
+    config: TokenizerConfig,
+    // `config.disabled_scopes` resolved to ids; names no rule has are left out
+    #[serde(skip)]
+    disabled_scopes: Vec<ScopeId>,
+}
+
+impl Grammar {
+    /// Loads a grammar from its JSON representation (`.tmLanguage.json`).
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_json(json)?;
+        Ok(builder.build()?.grammar(0))
+    }
+
+    /// Like [`Grammar::from_json`], but also reports what the grammar compiled
+    /// into, e.g. to find out why it is large or slow to tokenize with.
+    pub fn from_json_with_stats(json: &str) -> Result<(Self, CompileStats), Error> {
+        let parsed = parse::SyntaxDefinition::from_json(json).context(ParseSnafu)?;
+        let mut builder = SyntaxSetBuilder::new();
+        let (syntax, stats) =
+            compile::SyntaxDefinition::compile_with_stats(parsed, &mut builder.scopes)
+                .context(CompileSnafu)?;
+        builder.syntaxes.push(syntax);
+        Ok((builder.build()?.grammar(0), stats))
+    }
+
+    /// Like [`Grammar::from_json`], but allows comments and trailing commas
+    /// (JSONC), as in some grammars of VS Code extensions.
+    pub fn from_jsonc(jsonc: &str) -> Result<Self, Error> {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_jsonc(jsonc)?;
+        Ok(builder.build()?.grammar(0))
+    }
+
+    /// Like [`Grammar::from_json`], but streams the JSON from a reader, e.g. a
+    /// file, instead of holding all of it in memory.
+    ///
+    /// The reader is read in small pieces, so wrap unbuffered readers like a
+    /// [`File`](std::fs::File) in a [`BufReader`](std::io::BufReader).
+    pub fn from_reader(reader: impl Read) -> Result<Self, Error> {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_reader(reader)?;
+        Ok(builder.build()?.grammar(0))
+    }
+
+    /// Loads a grammar from its XML plist representation (`.tmLanguage`).
+    pub fn from_plist(xml: &str) -> Result<Self, Error> {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_plist(xml)?;
+        Ok(builder.build()?.grammar(0))
+    }
+
+    /// Loads a grammar from its YAML representation.
+    pub fn from_yaml(yaml: &str) -> Result<Self, Error> {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_yaml(yaml)?;
+        Ok(builder.build()?.grammar(0))
+    }
+
+    /// Reports every problem of a grammar in its JSON representation at once,
+    /// e.g. to validate a collection of grammars, where loading it stops at the
+    /// first one.
+    ///
+    /// Problems that only affect a single rule, like a regex the engine rejects,
+    /// don't keep the rest of the grammar from being checked. Includes aren't
+    /// resolved, see [`SyntaxSet::validate`] for those.
+    pub fn check_json(json: &str) -> Vec<Error> {
+        let parsed = match parse::SyntaxDefinition::from_json(json).context(ParseSnafu) {
+            Ok(parsed) => parsed,
+            Err(e) => return vec![e.into()],
+        };
+        let (_, errors) =
+            compile::SyntaxDefinition::compile_lenient(parsed, &mut Default::default());
+        errors
+            .into_iter()
+            .map(|source| InnerError::Compile { source }.into())
+            .collect()
+    }
+
+    /// Sets the limits and options that apply when tokenizing with this grammar.
+    pub fn with_config(self, config: TokenizerConfig) -> Self {
+        let disabled_scopes = config
+            .disabled_scopes
+            .iter()
+            .filter_map(|name| self.syntax_set.scopes.lookup(name))
+            .collect();
+        Self {
+            config,
+            disabled_scopes,
+            ..self
+        }
+    }
+
+    fn syntax(&self) -> &compile::SyntaxDefinition {
+        self.syntax_set.syntax(self.syntax_id)
+    }
+
+    fn tokenizer(&self) -> Tokenizer<'_> {
+        Tokenizer::new(&self.syntax_set, self.syntax_id)
+            .with_config(&self.config, &self.disabled_scopes)
+    }
+
+    /// The grammar's scope name, e.g. `source.rust`.
+    pub fn scope_name(&self) -> &str {
+        &self.syntax().scope_name.0
+    }
+
+    /// The grammar's display name, e.g. `Rust`, if it has one and it was kept;
+    /// see [`SyntaxSetBuilder::keep_metadata`].
+    pub fn name(&self) -> Option<&str> {
+        self.syntax().name.as_deref()
+    }
+
+    /// The grammar's `fileTypes`: extensions (`rs`) or whole file names
+    /// (`Makefile`), empty if they weren't kept.
+    pub fn file_types(&self) -> &[String] {
+        &self.syntax().file_types
+    }
+
+    /// Includes that didn't resolve to any rule, e.g. `source.css` when no grammar
+    /// with that scope name is loaded.
+    ///
+    /// As in TextMate, such includes are not an error: the patterns are
+    /// silently dropped and never match.
+    pub fn unresolved_includes(&self) -> impl Iterator<Item = String> + '_ {
+        self.syntax_set
+            .dropped_references
+            .iter()
+            .filter(|dropped| dropped.syntax_id == self.syntax_id)
+            .map(|dropped| dropped.reference.to_string())
+    }
+
+    /// Scope names this grammar's `injectTo` lists that no grammar in the set has.
+    ///
+    /// Like unresolved includes these are not an error, since the target grammars
+    /// might simply not be loaded.
+    pub fn missing_injection_targets(&self) -> impl Iterator<Item = &str> + '_ {
+        self.syntax_set
+            .missing_injection_targets
+            .iter()
+            .filter(|(syntax_id, _)| *syntax_id == self.syntax_id)
+            .map(|(_, target)| target.0.as_str())
+    }
+
+    /// Every scope the grammar's rules can emit, sorted, e.g. to check that a
+    /// theme covers them with [`Theme::uncovered_scopes`](crate::Theme::uncovered_scopes).
+    ///
+    /// Scopes of grammars this one includes are not listed.
+    pub fn scopes(&self) -> BTreeSet<&str> {
+        self.syntax().scopes(&self.syntax_set.scopes)
+    }
+
+    /// The keys of the grammar's repositories, sorted, e.g. to find out what
+    /// [`SyntaxSetBuilder::patch_rule`] can replace.
+    ///
+    /// Keys of nested repositories are listed too, but only the top level ones
+    /// can be patched. A key in several repositories is listed once.
+    pub fn repository_keys(&self) -> Vec<&str> {
+        self.syntax().repository_keys()
+    }
+
+    /// The rules of the grammar that emit `scope` with their `name` or
+    /// `contentName`, e.g. to find out which rule produced a token's scope with
+    /// [`Grammar::rule_origin`].
+    ///
+    /// Captures are rules too, so rules that only name a capture group are
+    /// included. Rules of grammars this one includes are not.
+    pub fn rules_with_scope(&self, scope: &str) -> Vec<RuleId> {
+        match self.syntax_set.scopes.lookup(scope) {
+            Some(scope_id) => self.syntax().rules_with_scope(scope_id),
+            None => Vec::new(),
+        }
+    }
+
+    /// Where in the grammar a rule was compiled from, as breadcrumbs like
+    /// `source.js > repository.string > patterns[2] > captures > 1`.
+    ///
+    /// Only recorded with the `rule-origins` feature; without it, or for an id of
+    /// another grammar, this is `None`. Identical rules are merged when the
+    /// grammar is compiled, and the merged rule keeps the first one's origin.
+    pub fn rule_origin(&self, rule_id: RuleId) -> Option<&str> {
+        self.syntax().rule_origin(rule_id)
+    }
+
+    /// The compiled grammar as text that only changes when the grammar compiles
+    /// differently, e.g. to diff what an edit to a grammar changed.
+    ///
+    /// Lists every rule in id order with its regexes, scopes, captures and
+    /// patterns, then the repositories sorted by key, the injections and the
+    /// regexes. Compiling the same grammar always gives the same text.
+    pub fn to_canonical_string(&self) -> String {
+        self.syntax().to_canonical_string(&self.syntax_set.scopes)
+    }
+
+    /// A readable overview of the compiled grammar, for debugging its structure.
+    ///
+    /// Lists the number of rules, regexes and repositories, then the tree of
+    /// rules with their patterns and scopes. The format is not stable.
+    pub fn dump_summary(&self) -> String {
+        self.syntax().dump_summary(&self.syntax_set.scopes)
+    }
+
+    /// Compiles every regex of the grammar's set now instead of on first use, e.g.
+    /// to warm up a service, and fails on the first one the regex engine rejects.
+    ///
+    /// Every grammar of the set is compiled, since includes can reach any of
+    /// them. End patterns that refer to their begin captures can only be compiled
+    /// once those are known, while tokenizing. Calling it again is cheap.
+    pub fn precompile_regexes(&self) -> Result<(), Error> {
+        for syntax in &self.syntax_set.syntaxes {
+            syntax.precompile_regexes().context(PrecompileSnafu {
+                scope_name: &syntax.scope_name.0,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Splits a single line into tokens covering the whole line.
+    ///
+    /// `state` is updated in place, so that rules spanning multiple lines
+    /// (e.g. block comments) carry over to the next call.
+    pub fn tokenize_line(&self, line: &str, state: &mut TokenizerState) -> Vec<Token> {
+        self.tokenizer().tokenize_line(line, state)
+    }
+
+    /// Like [`Grammar::tokenize_line`], but leaves `state` as it is and returns
+    /// the state after the line instead, e.g. to tokenize a line speculatively
+    /// or from a state saved for a line further up.
+    ///
+    /// The state holds all the line depends on, including end patterns made
+    /// from what their begin pattern captured on an earlier line.
+    pub fn tokenize_from(
+        &self,
+        line: &str,
+        state: &TokenizerState,
+    ) -> (Vec<Token>, TokenizerState) {
+        let mut state = state.clone();
+        let tokens = self.tokenize_line(line, &mut state);
+        (tokens, state)
+    }
+
+    /// Like [`Grammar::tokenize_line`], but for lines that might not be valid
+    /// UTF-8, e.g. of binary or mixed-encoding files.
+    ///
+    /// Patterns see every invalid sequence as a replacement character (U+FFFD),
+    /// which only matches patterns that match any character. Token ranges are
+    /// always in bytes of `line`, whatever the configured
+    /// [`offset_mode`](TokenizerConfig::offset_mode).
+    pub fn tokenize_bytes(&self, line: &[u8], state: &mut TokenizerState) -> Vec<Token> {
+        let config = TokenizerConfig {
+            offset_mode: OffsetMode::Byte,
+            ..self.config.clone()
+        };
+        let tokenizer = Tokenizer::new(&self.syntax_set, self.syntax_id)
+            .with_config(&config, &self.disabled_scopes);
+        if let Ok(line) = std::str::from_utf8(line) {
+            return tokenizer.tokenize_line(line, state);
+        }
+
+        let (text, pieces) = decode_lossy(line);
+        // tokens end on character boundaries, so never inside a replacement
+        let original = |offset: usize| {
+            let piece = pieces.partition_point(|&(decoded, _)| decoded <= offset) - 1;
+            let (decoded, original) = pieces[piece];
+            original + (offset - decoded)
+        };
+        let mut tokens = tokenizer.tokenize_line(&text, state);
+        for token in &mut tokens {
+            token.range = original(token.range.start)..original(token.range.end);
+        }
+        tokens
+    }
+
+    /// Tokenizes a whole text, returning the tokens of every line.
+    ///
+    /// Lines are split on `\n`, with an optional preceding `\r`. As in vscode-textmate,
+    /// every line is matched with a terminating `\n`, so that patterns ending on
+    /// a newline work, but the returned tokens never include the line terminator.
+    pub fn tokenize_file(&self, text: &str) -> Vec<Vec<Token>> {
+        self.tokenize_iter(text).collect()
+    }
+
+    /// Like [`Grammar::tokenize_file`], but tokenizes lazily, one line per item.
+    ///
+    /// The state between lines is kept inside the iterator, so every call starts
+    /// from the beginning of `text` again.
+    pub fn tokenize_iter<'a>(&'a self, text: &'a str) -> impl Iterator<Item = Vec<Token>> + 'a {
+        let mut state = TokenizerState::default();
+        text.split('\n').map(move |line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            self.tokenize_unterminated_line(line, &mut state)
+        })
+    }
+
+    /// Where the begin/end and begin/while regions of `text` start and end, e.g.
+    /// to build fold regions, instead of its tokens.
+    ///
+    /// A region starts where its begin pattern matches and ends where its end
+    /// pattern does, on the same line or a later one. Every push is matched by a
+    /// pop; regions still open at the end of the text end there. Regions of rules
+    /// with neither a name nor a contentName aren't reported.
+    pub fn tokenize_events(&self, text: &str) -> Vec<ScopeEvent> {
+        let tokenizer = self.tokenizer();
+        let mode = self.config.offset_mode;
+        let mut state = TokenizerState::default();
+        let mut events = Vec::new();
+        // scopes of the regions that are open, innermost last
+        let mut open = Vec::new();
+        let mut end = Position { line: 0, offset: 0 };
+        for (idx, line) in text.split('\n').enumerate() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let (_, regions) = tokenizer.tokenize_regions(&format!("{line}\n"), &mut state);
+            let position = |offset: usize| Position {
+                line: idx,
+                offset: mode.len_of(&line[..offset.min(line.len())]),
+            };
+            for region in regions {
+                match region {
+                    RegionChange::Enter(offset, scope) => {
+                        let scope = scope.map(|scope| self.resolve_scope(scope).to_string());
+                        if let Some(scope) = &scope {
+                            events.push(ScopeEvent::Push(scope.clone(), position(offset)));
+                        }
+                        open.push(scope);
+                    }
+                    RegionChange::Leave(offset) => {
+                        if let Some(Some(scope)) = open.pop() {
+                            events.push(ScopeEvent::Pop(scope, position(offset)));
+                        }
+                    }
+                }
+            }
+            end = position(line.len());
+        }
+        while let Some(scope) = open.pop() {
+            events.extend(scope.map(|scope| ScopeEvent::Pop(scope, end)));
+        }
+        events
+    }
+
+    /// Like [`Grammar::tokenize_file`], but splits the text into a chunk per
+    /// thread and tokenizes the chunks in parallel with rayon.
+    ///
+    /// The result is always the same as that of `tokenize_file`. Every chunk is
+    /// tokenized as if it started the text, which is only right if the chunk
+    /// before it ends outside of any multi-line construct (e.g. a block
+    /// comment). This is checked afterwards, in order: where it's wrong, the
+    /// chunk is tokenized again from the right state, sequentially, until the
+    /// states agree again.
+    ///
+    /// Finding the lines where nothing is open before splitting would mean
+    /// matching every line sequentially, which is most of the work: on the text
+    /// of `benches/parallel.rs` it takes three quarters of the time of
+    /// `tokenize_file`. When the guess is right, which is the common case of text
+    /// with few long multi-line constructs, it costs next to nothing, and the
+    /// work is about that of `tokenize_file`, split between the threads. In the
+    /// worst case, e.g. a file that's one long comment, the work is nearly
+    /// doubled and most of it is sequential.
+    #[cfg(feature = "rayon")]
+    pub fn tokenize_file_parallel(&self, text: &str) -> Vec<Vec<Token>> {
+        use rayon::prelude::*;
+
+        let lines: Vec<_> = text
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .collect();
+        let chunk_len = lines
+            .len()
+            .div_ceil(rayon::current_num_threads())
+            .max(MIN_PARALLEL_CHUNK_LINES);
+        if chunk_len >= lines.len() {
+            return self.tokenize_file(text);
+        }
+
+        let chunks: Vec<_> = lines
+            .par_chunks(chunk_len)
+            .map(|chunk| {
+                let mut states = Vec::new();
+                let tokens = self.retokenize(chunk, &mut states, 0..chunk.len());
+                (tokens, states)
+            })
+            .collect();
+        let mut tokens = Vec::with_capacity(lines.len());
+        let mut states = Vec::with_capacity(lines.len());
+        for (chunk_tokens, chunk_states) in chunks {
+            tokens.extend(chunk_tokens);
+            states.extend(chunk_states);
+        }
+
+        // fixing a chunk can run into the next ones, which are then fixed already
+        let mut fixed_until = 0;
+        for start in (chunk_len..lines.len()).step_by(chunk_len) {
+            if start < fixed_until {
+                continue;
+            }
+            let fixed = self.retokenize(&lines, &mut states, start..start + 1);
+            fixed_until = start + fixed.len();
+            tokens.splice(start..fixed_until, fixed);
+        }
+        tokens
+    }
+
+    /// Re-tokenizes a text after the lines in `changed` were edited, e.g. in an
+    /// editor.
+    ///
+    /// `lines` are the lines of the edited text, without terminators, and
+    /// `states[i]` is the state at the end of line `i`, as left by the previous
+    /// call. Entries of `changed` can hold anything, and `states` is resized to
+    /// match `lines`, so when lines were inserted or removed, splice `states`
+    /// the same way first. Tokenizing stops at the first line after `changed`
+    /// whose end state is the same as before, since nothing after it changes.
+    ///
+    /// Returns the tokens of lines `changed.start..changed.start + len`; `states`
+    /// is updated in place. Start with an empty `states` and `0..lines.len()` to
+    /// tokenize the whole text.
+    pub fn retokenize(
+        &self,
+        lines: &[&str],
+        states: &mut Vec<TokenizerState>,
+        changed: Range<usize>,
+    ) -> Vec<Vec<Token>> {
+        states.resize_with(lines.len(), TokenizerState::default);
+
+        let mut state = match changed.start {
+            0 => TokenizerState::default(),
+            start => states[start - 1].clone(),
+        };
+        let mut tokens = Vec::new();
+        for (idx, line) in lines.iter().enumerate().skip(changed.start) {
+            tokens.push(self.tokenize_unterminated_line(line, &mut state));
+
+            let converged = idx + 1 >= changed.end && state == states[idx];
+            states[idx] = state.clone();
+            if converged {
+                break;
+            }
+        }
+        tokens
+    }
+
+    // tokenizes a line of a text with its terminator, as vscode-textmate does,
+    // but without returning tokens for it
+    fn tokenize_unterminated_line(&self, line: &str, state: &mut TokenizerState) -> Vec<Token> {
+        let terminated = format!("{line}\n");
+
+        let mut tokens = self.tokenize_line(&terminated, state);
+        let line_len = self.config.offset_mode.len_of(line);
+        tokens.retain_mut(|token| {
+            token.range.end = token.range.end.min(line_len);
+            !token.range.is_empty()
+        });
+        tokens
+    }
+
+    /// Like [`Grammar::tokenize_line`], but returns interned scope ids, which is
+    /// the cheapest form: most tokens don't allocate at all.
+    ///
+    /// Turn the ids into names with [`Grammar::resolve_scope`] when needed.
+    pub fn tokenize_to_ids(
+        &self,
+        line: &str,
+        state: &mut TokenizerState,
+    ) -> Vec<(Range<usize>, ScopeStack)> {
+        self.tokenizer().tokenize(line, state)
+    }
+
+    /// The name of a scope id returned by [`Grammar::tokenize_to_ids`].
+    ///
+    /// Ids are shared by the grammars of a set; an id from another set resolves to
+    /// an unrelated name, or panics.
+    pub fn resolve_scope(&self, id: ScopeId) -> &str {
+        self.syntax_set.scopes.resolve(id)
+    }
+
+    /// Like [`Grammar::tokenize_line`], but returns the scopes as slices borrowed
+    /// from the grammar instead of allocating a string per scope.
+    ///
+    /// Ranges cover `line` exactly, in the unit of
+    /// [`TokenizerConfig::offset_mode`].
+    pub fn tokenize_to_scopes(
+        &self,
+        line: &str,
+        state: &mut TokenizerState,
+    ) -> Vec<(Range<usize>, Vec<&str>)> {
+        self.tokenizer().tokenize_to_scopes(line, state)
+    }
+}
+
+// below this, splitting a text costs more than tokenizing it in parallel saves
+#[cfg(feature = "rayon")]
+const MIN_PARALLEL_CHUNK_LINES: usize = 256;
+
+/// A set of grammars that can include each other, e.g. HTML with embedded
+/// JavaScript and CSS.
+///
+/// Built with a [`SyntaxSetBuilder`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyntaxSet {
+    syntax_set: Arc<compile::SyntaxSet>,
+}
+
+impl SyntaxSet {
+    /// The grammars of the set, in the order they were added.
+    pub fn grammars(&self) -> impl Iterator<Item = Grammar> + '_ {
+        (0..self.syntax_set.syntaxes.len()).map(|idx| self.grammar(idx))
+    }
+
+    /// Finds the grammar with the given scope name, e.g. `source.rust`.
+    pub fn find_by_scope(&self, scope_name: &str) -> Option<Grammar> {
+        let scope_name = compile::ScopeName(scope_name.to_string());
+        let syntax_id = self.syntax_set.find_syntax(&scope_name)?;
+        Some(self.grammar(syntax_id.to_idx()))
+    }
+
+    /// Finds the grammar for a file extension, e.g. `rs` or `.rs`.
+    ///
+    /// Extensions are taken from the grammars' `fileTypes`; if several grammars
+    /// list the same one, the one added first wins.
+    pub fn find_by_extension(&self, extension: &str) -> Option<Grammar> {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        let syntax_id = self.syntax_set.find_syntax_by_file_type(extension)?;
+        Some(self.grammar(syntax_id.to_idx()))
+    }
+
+    /// Finds the first grammar whose `firstLineMatch` matches `line`, e.g. a
+    /// `#!/bin/sh` shebang.
+    ///
+    /// Grammars without `firstLineMatch`, or with one that fails to compile,
+    /// never match.
+    pub fn find_by_first_line(&self, line: &str) -> Option<Grammar> {
+        let idx = self.syntax_set.syntaxes.iter().position(|syntax| {
+            syntax
+                .first_line_match
+                .as_ref()
+                .and_then(|regex| regex.get().compiled().ok())
+                .is_some_and(|regex| matches!(regex.search_from(line, 0, None), Ok(Some(_))))
+        })?;
+        Some(self.grammar(idx))
+    }
+
+    /// Lints the grammars of the set: includes that don't resolve, repository
+    /// rules that are never included and rules that match nothing.
+    ///
+    /// None of these stop a grammar from loading; as in TextMate, they are
+    /// silently dropped.
+    pub fn validate(&self) -> Vec<ValidationWarning> {
+        self.syntax_set.validate()
+    }
+
+    fn grammar(&self, idx: usize) -> Grammar {
+        Grammar {
+            syntax_set: self.syntax_set.clone(),
+            syntax_id: compile::SyntaxId::from_idx(idx),
+            config: TokenizerConfig::default(),
+            disabled_scopes: Vec::new(),
+        }
+    }
+}
+
+/// Collects grammars into a [`SyntaxSet`].
+///
+/// Grammars are compiled as they are added; includes of other grammars are
+/// resolved once all of them are known, in [`SyntaxSetBuilder::build`].
+#[derive(Debug, Default)]
+pub struct SyntaxSetBuilder {
+    syntaxes: Vec<compile::SyntaxDefinition>,
+    scopes: compile::ScopeInterner,
+    drop_metadata: bool,
+    prune_unreachable: bool,
+}
+
+// `line` with every invalid sequence replaced by U+FFFD, and where the pieces
+// of the decoded text start in `line`, as (decoded, original) offsets; the end of
+// both is the last piece
+fn decode_lossy(line: &[u8]) -> (String, Vec<(usize, usize)>) {
+    let mut text = String::with_capacity(line.len());
+    let mut pieces = Vec::new();
+    let mut original = 0;
+    for chunk in line.utf8_chunks() {
+        pieces.push((text.len(), original));
+        text.push_str(chunk.valid());
+        original += chunk.valid().len();
+        if !chunk.invalid().is_empty() {
+            pieces.push((text.len(), original));
+            text.push(char::REPLACEMENT_CHARACTER);
+            original += chunk.invalid().len();
+        }
+    }
+    pieces.push((text.len(), original));
+    (text, pieces)
+}
+
+impl SyntaxSetBuilder {
+    /// Creates a builder with no grammars.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether grammars added from now on keep their display `name` and
+    /// `fileTypes`, which they do by default.
+    ///
+    /// Without them, [`SyntaxSet::find_by_extension`] doesn't find the grammars.
+    /// Worth dropping when grammars are only ever looked up by scope name.
+    pub fn keep_metadata(&mut self, keep: bool) -> &mut Self {
+        self.drop_metadata = !keep;
+        self
+    }
+
+    /// Whether whitespace around the scope names of grammars added from now on
+    /// is trimmed, e.g. `"string.quoted "`, which themes would otherwise miss.
+    ///
+    /// Off by default, so that scopes are exactly as the grammar writes them.
+    pub fn trim_scope_names(&mut self, trim: bool) -> &mut Self {
+        self.scopes.trim = trim;
+        self
+    }
+
+    /// Whether the scope names of grammars added from now on are lowercased, for
+    /// grammars that are inconsistent about case. Off by default; theme selectors
+    /// are matched as written, so they should be lowercase, too.
+    pub fn lowercase_scope_names(&mut self, lowercase: bool) -> &mut Self {
+        self.scopes.lowercase = lowercase;
+        self
+    }
+
+    /// Whether [`SyntaxSetBuilder::build`] drops the rules that no grammar of the
+    /// set can reach, e.g. repository rules nothing includes, and the regexes only
+    /// they used, to make the set smaller.
+    ///
+    /// Off by default, since [`SyntaxSet::validate`] doesn't see what was dropped:
+    /// unused repository rules and unresolved includes in them aren't reported.
+    pub fn prune_unreachable_rules(&mut self, prune: bool) -> &mut Self {
+        self.prune_unreachable = prune;
+        self
+    }
+
+    /// Adds a grammar in its JSON representation (`.tmLanguage.json`).
+    pub fn add_from_json(&mut self, json: &str) -> Result<(), Error> {
+        let parsed = parse::SyntaxDefinition::from_json(json).context(ParseSnafu)?;
+        self.add(parsed)
+    }
+
+    /// Adds a grammar in its JSON representation, allowing comments and trailing
+    /// commas (JSONC), as VS Code extensions sometimes ship them.
+    pub fn add_from_jsonc(&mut self, jsonc: &str) -> Result<(), Error> {
+        let parsed = parse::SyntaxDefinition::from_jsonc(jsonc).context(ParseSnafu)?;
+        self.add(parsed)
+    }
+
+    /// Adds a grammar in its JSON representation, streamed from a reader; see
+    /// [`Grammar::from_reader`].
+    pub fn add_from_reader(&mut self, reader: impl Read) -> Result<(), Error> {
+        let parsed = parse::SyntaxDefinition::from_reader(reader).context(ParseSnafu)?;
+        self.add(parsed)
+    }
+
+    /// Adds a grammar in its XML plist representation (`.tmLanguage`).
+    pub fn add_from_plist(&mut self, xml: &str) -> Result<(), Error> {
+        let parsed = parse::SyntaxDefinition::from_plist(xml).context(ParseSnafu)?;
+        self.add(parsed)
+    }
+
+    /// Adds a grammar in its YAML representation.
+    pub fn add_from_yaml(&mut self, yaml: &str) -> Result<(), Error> {
+        let parsed = parse::SyntaxDefinition::from_yaml(yaml).context(ParseSnafu)?;
+        self.add(parsed)
+    }
+
+    fn add(&mut self, mut parsed: parse::SyntaxDefinition) -> Result<(), Error> {
+        if self.drop_metadata {
+            parsed.name = None;
+            parsed.file_types = None;
+        }
+        let syntax =
+            compile::SyntaxDefinition::compile(parsed, &mut self.scopes).context(CompileSnafu)?;
+        self.syntaxes.push(syntax);
+        Ok(())
+    }
+
+    /// Replaces a rule of the top level repository of an added grammar, e.g. to
+    /// fix a broken regex or change a scope name without editing the grammar.
+    ///
+    /// `rule_json` is a rule as it would appear in the grammar's JSON under
+    /// `repository.<key>`; an empty rule (`{}`) disables the key. Includes of
+    /// this grammar's own repository rules must resolve.
+    pub fn patch_rule(
+        &mut self,
+        scope_name: &str,
+        key: &str,
+        rule_json: &str,
+    ) -> Result<(), Error> {
+        let raw_rule = parse::Rule::from_json(rule_json).context(ParseSnafu)?;
+        let syntax = self
+            .syntaxes
+            .iter_mut()
+            .find(|syntax| syntax.scope_name.0 == scope_name)
+            .context(PatchTargetSnafu { scope_name })?;
+
+        // patching consumes the grammar, which has to stay intact if it fails
+        *syntax = syntax
+            .clone()
+            .patch(&mut self.scopes, key, raw_rule)
+            .context(PatchSnafu { scope_name })?;
+        Ok(())
+    }
+
+    /// Resolves includes between the grammars.
+    ///
+    /// Fails if more than one grammar has the same scope name, since includes
+    /// couldn't tell them apart.
+    pub fn build(self) -> Result<SyntaxSet, Error> {
+        let mut syntax_set =
+            compile::SyntaxSet::new(self.syntaxes, self.scopes).context(LinkSnafu)?;
+        syntax_set.link();
+        if self.prune_unreachable {
+            syntax_set.prune_unreachable();
+        }
+        Ok(SyntaxSet {
+            syntax_set: Arc::new(syntax_set),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::OffsetMode;
+
+    const LETTERS_TMLANG: &str = r#"{
+      "scopeName": "source.letters",
+      "patterns": [{ "match": "a|b|c", "name": "keyword.letter" }]
+    }"#;
+
+    #[test]
+    fn can_tokenize_with_grammar() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG).unwrap();
+        assert_eq!(grammar.scope_name(), "source.letters");
+
+        let tokens = grammar.tokenize_line("xa", &mut TokenizerState::default());
+        assert_eq!(
+            tokens,
+            vec![
+                Token {
+                    range: 0..1,
+                    scopes: vec!["source.letters".to_string()]
+                },
+                Token {
+                    range: 1..2,
+                    scopes: vec!["source.letters".to_string(), "keyword.letter".to_string()]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_a_whole_grammar() {
+        let errors = Grammar::check_json(
+            r#"{
+              "scopeName": "source.broken",
+              "patterns": [{ "match": "(" }, { "match": "[" }]
+            }"#,
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(Grammar::check_json(LETTERS_TMLANG).is_empty());
+        assert!(matches!(&Grammar::check_json("{")[..], [_]));
+    }
+
+    #[test]
+    fn starts_every_token_with_the_base_scope() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG).unwrap();
+
+        let lines = grammar.tokenize_file("a\n\nxb");
+        let outermost: Vec<_> = lines
+            .iter()
+            .flatten()
+            .map(|token| token.scopes[0].as_str())
+            .collect();
+        assert_eq!(outermost, [grammar.scope_name(); 3]);
+    }
+
+    #[test]
+    fn tokenizes_to_borrowed_scopes() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG).unwrap();
+        let mut state = TokenizerState::default();
+
+        let scopes = grammar.tokenize_to_scopes("xab", &mut state);
+        assert_eq!(
+            scopes,
+            vec![
+                (0..1, vec!["source.letters"]),
+                (1..2, vec!["source.letters", "keyword.letter"]),
+                (2..3, vec!["source.letters", "keyword.letter"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_to_interned_scopes() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG).unwrap();
+        let mut state = TokenizerState::default();
+
+        let tokens = grammar.tokenize_to_ids("xa", &mut state);
+        let [base] = tokens[0].1[..] else {
+            panic!("expected only the base scope");
+        };
+        assert_eq!(grammar.resolve_scope(base), "source.letters");
+        let [_, letter] = tokens[1].1[..] else {
+            panic!("expected a single scope on top of the base scope");
+        };
+        assert_eq!(grammar.resolve_scope(letter), "keyword.letter");
+        assert!(!tokens[1].1.spilled());
+    }
+
+    #[test]
+    fn tokenizes_files_line_by_line() {
+        let grammar = Grammar::from_json(
+            r##"{
+              "scopeName": "source.comments",
+              "patterns": [
+                { "begin": "#", "end": "\\n", "name": "comment" },
+                { "begin": "\\(", "end": "\\)", "name": "group" }
+              ]
+            }"##,
+        )
+        .unwrap();
+
+        let lines: Vec<Vec<(Range<usize>, Vec<String>)>> = grammar
+            .tokenize_file("a#b\r\n(c\n\nd)")
+            .into_iter()
+            .map(|tokens| tokens.into_iter().map(|t| (t.range, t.scopes)).collect())
+            .collect();
+
+        let base = vec!["source.comments".to_string()];
+        let comment = vec!["source.comments".to_string(), "comment".to_string()];
+        let group = vec!["source.comments".to_string(), "group".to_string()];
+        assert_eq!(
+            lines,
+            vec![
+                vec![(0..1, base), (1..2, comment.clone()), (2..3, comment)],
+                vec![(0..1, group.clone()), (1..2, group.clone())],
+                vec![],
+                vec![(0..1, group.clone()), (1..2, group)],
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_lazily() {
+        let grammar = Grammar::from_json(
+            r#"{ "scopeName": "source.groups", "patterns": [{ "begin": "\\(", "end": "\\)", "name": "group" }] }"#,
+        )
+        .unwrap();
+        let text = "(a\nb)\nc";
+
+        // state carries over between lines, but not between iterators
+        let mut lines = grammar.tokenize_iter(text);
+        assert_eq!(lines.next().unwrap()[1].scopes, ["source.groups", "group"]);
+        assert_eq!(lines.next().unwrap()[0].scopes, ["source.groups", "group"]);
+        assert_eq!(grammar.tokenize_iter(text).count(), 3);
+        assert_eq!(
+            grammar.tokenize_iter(text).collect::<Vec<_>>(),
+            grammar.tokenize_file(text)
+        );
+    }
+
+    #[test]
+    fn retokenizes_until_states_converge() {
+        let grammar = Grammar::from_json(
+            r#"{ "scopeName": "source.comments", "patterns": [{ "begin": "/\\*", "end": "\\*/", "name": "comment" }] }"#,
+        )
+        .unwrap();
+        let mut lines = vec!["a", "b", "c", "d"];
+        let mut states = Vec::new();
+        assert_eq!(grammar.retokenize(&lines, &mut states, 0..4).len(), 4);
+
+        // opening a comment changes every line after it
+        lines[1] = "/*";
+        let tokens = grammar.retokenize(&lines, &mut states, 1..2);
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[2][0].scopes, ["source.comments", "comment"]);
+
+        // editing inside it only changes that line
+        lines[2] = "x";
+        assert_eq!(grammar.retokenize(&lines, &mut states, 2..3).len(), 1);
+
+        let whole = lines.join("\n");
+        let mut fresh = Vec::new();
+        assert_eq!(
+            grammar.retokenize(&lines, &mut fresh, 0..lines.len()),
+            grammar.tokenize_file(&whole)
+        );
+        assert_eq!(fresh, states);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn tokenizes_in_parallel_like_sequentially() {
+        let grammar = Grammar::from_json(
+            r#"{ "scopeName": "source.comments", "patterns": [
+              { "begin": "/\\*", "end": "\\*/", "name": "comment" },
+              { "match": "a", "name": "letter" }
+            ] }"#,
+        )
+        .unwrap();
+
+        // comments of all lengths, so that some span the chunk boundaries
+        let mut text = String::new();
+        for idx in 0..MIN_PARALLEL_CHUNK_LINES * 8 {
+            let comment = idx % 7 == 0;
+            text.push_str(match (comment, idx % 300 < 150) {
+                (true, true) => "a /* a\r\n",
+                (true, false) => "a */ a\n",
+                (false, _) => "a\n",
+            });
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        assert_eq!(
+            pool.install(|| grammar.tokenize_file_parallel(&text)),
+            grammar.tokenize_file(&text)
+        );
+
+        let short = "/* a\na */ a";
+        assert_eq!(
+            grammar.tokenize_file_parallel(short),
+            grammar.tokenize_file(short)
+        );
+    }
+
+    #[test]
+    fn round_trips_through_serde() {
+        let grammar = Grammar::from_json(
+            r##"{
+              "scopeName": "source.comments",
+              "patterns": [
+                { "begin": "#", "end": "$", "name": "comment", "patterns": [{ "include": "#letter" }] },
+                { "include": "#letter" }
+              ],
+              "repository": { "letter": { "match": "a|b", "name": "keyword.letter" } }
+            }"##,
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&grammar).unwrap();
+        let reloaded: Grammar = serde_json::from_str(&json).unwrap();
+
+        let text = "a b c # a\nb";
+        assert_eq!(reloaded.scope_name(), grammar.scope_name());
+        assert_eq!(reloaded.tokenize_file(text), grammar.tokenize_file(text));
+    }
+
+    #[test]
+    fn leaves_the_rest_of_the_line_when_limits_are_hit() {
+        let grammar = Grammar::from_json(
+            r#"{
+              "scopeName": "source.slow",
+              "patterns": [{ "match": "x", "name": "x" }, { "match": "(a+)+b", "name": "slow" }]
+            }"#,
+        )
+        .unwrap();
+        let line = format!("x{}", "a".repeat(30));
+        let scopes = |config| {
+            let grammar = grammar.clone().with_config(config);
+            let tokens = grammar.tokenize_line(&line, &mut TokenizerState::default());
+            tokens
+                .into_iter()
+                .map(|t| (t.range, t.scopes))
+                .collect::<Vec<_>>()
+        };
+
+        let base = || vec!["source.slow".to_string()];
+        let untokenized = vec![(0..line.len(), base())];
+        let max_line_len = TokenizerConfig {
+            max_line_len: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(scopes(max_line_len), untokenized);
+        let max_match_steps = TokenizerConfig {
+            max_match_steps: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            scopes(max_match_steps),
+            vec![
+                (0..1, vec!["source.slow".to_string(), "x".to_string()]),
+                (1..line.len(), base())
+            ]
+        );
+        let time_limit = TokenizerConfig {
+            time_limit: Some(std::time::Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(scopes(time_limit), untokenized);
+    }
+
+    #[test]
+    fn reports_ranges_in_utf16_code_units() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG)
+            .unwrap()
+            .with_config(TokenizerConfig {
+                offset_mode: OffsetMode::Utf16,
+                ..Default::default()
+            });
+
+        // the emoji is 4 bytes but 2 code units, the é 2 bytes but 1 code unit
+        let line = "é😀a b";
+        let ranges: Vec<_> = grammar
+            .tokenize_to_scopes(line, &mut TokenizerState::default())
+            .into_iter()
+            .map(|(range, _)| range)
+            .collect();
+        assert_eq!(ranges, [0..3, 3..4, 4..5, 5..6]);
+
+        let lines = grammar.tokenize_file("😀a\r\nb😀");
+        let ranges: Vec<Vec<_>> = lines
+            .iter()
+            .map(|tokens| tokens.iter().map(|t| t.range.clone()).collect())
+            .collect();
+        assert_eq!(ranges, [vec![0..2, 2..3], vec![0..1, 1..3]]);
+    }
+
+    #[test]
+    fn reports_compile_stats() {
+        let (grammar, stats) = Grammar::from_json_with_stats(
+            r##"{
+              "scopeName": "source.stats",
+              "patterns": [
+                { "include": "#string" },
+                { "include": "#missing" },
+                { "match": "a", "name": "letter" },
+                { "begin": "b", "while": "b" }
+              ],
+              "repository": {
+                "string": {
+                  "patterns": [
+                    { "begin": "\"", "end": "\"", "patterns": [{ "match": "a", "name": "letter" }] },
+                    { "include": "#nested" }
+                  ],
+                  "repository": {
+                    "nested": { "patterns": [{ "include": "#string" }, { "match": "c" }] }
+                  }
+                }
+              }
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(grammar.scope_name(), "source.stats");
+        assert_eq!(
+            stats,
+            CompileStats {
+                // `a` is matched in two repository scopes, so it's two rules
+                match_rules: 3,
+                begin_end_rules: 1,
+                begin_while_rules: 1,
+                include_only_rules: 3,
+                regexes_written: 7,
+                regexes: 6,
+                max_repository_depth: 2,
+                unresolved_references: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn tokenizes_invalid_utf8() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG).unwrap();
+
+        let tokenize = |line: &[u8]| -> Vec<_> {
+            (grammar
+                .tokenize_bytes(line, &mut TokenizerState::default())
+                .into_iter())
+            .map(|token| (token.range, token.scopes.len()))
+            .collect()
+        };
+        // a lone continuation byte, and a sequence cut short
+        assert_eq!(
+            tokenize(b"a\x80b\xe2\x82c"),
+            [(0..1, 2), (1..2, 1), (2..3, 2), (3..5, 1), (5..6, 2)]
+        );
+        assert_eq!(tokenize(b"\xff"), [(0..1, 1)]);
+        assert_eq!(tokenize("é a".as_bytes()), [(0..3, 1), (3..4, 2)]);
+    }
+
+    #[test]
+    fn reports_ranges_in_chars() {
+        let grammar = Grammar::from_json(LETTERS_TMLANG)
+            .unwrap()
+            .with_config(TokenizerConfig {
+                offset_mode: OffsetMode::Char,
+                ..Default::default()
+            });
+
+        let line = "é😀a b";
+        let ranges: Vec<_> = grammar
+            .tokenize_line(line, &mut TokenizerState::default())
+            .into_iter()
+            .map(|token| token.range)
+            .collect();
+        assert_eq!(ranges, [0..2, 2..3, 3..4, 4..5]);
+
+        // the start of `a` in every unit
+        let char = OffsetMode::Char;
+        assert_eq!(char.convert(line, 2, OffsetMode::Byte), Some(6));
+        assert_eq!(char.convert(line, 2, OffsetMode::Utf16), Some(3));
+        assert_eq!(OffsetMode::Utf16.convert(line, 3, char), Some(2));
+        assert_eq!(OffsetMode::Byte.convert(line, 6, char), Some(2));
+        // inside the emoji, and past the end
+        assert_eq!(OffsetMode::Utf16.convert(line, 2, char), None);
+        assert_eq!(OffsetMode::Byte.convert(line, 4, char), None);
+        assert_eq!(char.convert(line, 6, OffsetMode::Byte), None);
+    }
+
+    #[test]
+    fn flags_begin_matches_past_the_scope_depth() {
+        let grammar = Grammar::from_json(
+            r#"{ "scopeName": "source.groups", "patterns": [{ "begin": "\\(", "end": "\\)", "name": "group", "patterns": [{ "include": "$self" }] }] }"#,
+        )
+        .unwrap()
+        .with_config(TokenizerConfig {
+            max_scope_depth: Some(3),
+            ..Default::default()
+        });
+
+        let tokens = grammar.tokenize_line("((()))", &mut TokenizerState::default());
+        let scopes: Vec<_> = tokens.iter().map(|t| t.scopes.join(" ")).collect();
+        assert_eq!(
+            scopes,
+            [
+                "source.groups group",
+                "source.groups group group",
+                "source.groups group group invalid.illegal.scope-depth-exceeded",
+                "source.groups group group",
+                "source.groups group",
+                "source.groups",
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_off_leading_whitespace() {
+        let grammar = Grammar::from_json(
+            r#"{
+              "scopeName": "source.indented",
+              "patterns": [
+                { "match": "let", "name": "keyword" },
+                { "match": "\\t", "name": "tab" },
+                { "begin": "/\\*", "end": "\\*/", "name": "comment" }
+              ]
+            }"#,
+        )
+        .unwrap();
+        let tokenize = |grammar: &Grammar| {
+            let mut state = TokenizerState::default();
+            let lines = ["    x = 1", "\tlet", "/*", "  a */", "  ", " \t x"];
+            lines
+                .iter()
+                .map(|line| {
+                    let tokens = grammar.tokenize_line(line, &mut state);
+                    tokens.iter().map(|t| t.text(line)).collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let lines = tokenize(&grammar);
+        assert_eq!(lines[0], ["    x = 1"]);
+        assert_eq!(lines[3], ["  a ", "*/"]);
+        assert_eq!(lines[5], [" ", "\t", " x"]);
+
+        let grammar = grammar.with_config(TokenizerConfig {
+            split_leading_whitespace: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            tokenize(&grammar),
+            [
+                vec!["    ", "x = 1"],
+                vec!["\t", "let"],
+                vec!["/*"],
+                vec!["  ", "a ", "*/"],
+                vec!["  "],
+                vec![" \t ", "x"],
+            ]
+        );
+        // the indentation inside the comment is left out of it
+        let mut state = TokenizerState::default();
+        grammar.tokenize_line("/*", &mut state);
+        let tokens = grammar.tokenize_line("  a */", &mut state);
+        assert_eq!(tokens[0].scopes, ["source.indented"]);
+        assert_eq!(tokens[1].scopes, ["source.indented", "comment"]);
+    }
+
+    #[test]
+    fn skips_rules_with_disabled_scopes() {
+        let grammar = Grammar::from_json(
+            r#"{
+              "scopeName": "source.disabled",
+              "patterns": [
+                { "begin": "<", "end": ">", "name": "meta.embedded", "patterns": [{ "match": "a", "name": "inner.letter" }] },
+                { "match": "a", "name": "outer.letter" }
+              ]
+            }"#,
+        )
+        .unwrap()
+        .with_config(TokenizerConfig {
+            disabled_scopes: ["meta.embedded".to_string(), "no.such.scope".to_string()].into(),
+            ..Default::default()
+        });
+
+        let tokens = grammar.tokenize_line("<a>", &mut TokenizerState::default());
+        let scopes: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.range.clone(), t.scopes.join(" ")))
+            .collect();
+        assert_eq!(
+            scopes,
+            [
+                (0..1, "source.disabled".to_string()),
+                (1..2, "source.disabled outer.letter".to_string()),
+                (2..3, "source.disabled".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn injects_grammars_into_their_targets() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder
+            .add_from_json(
+                r#"{ "scopeName": "source.js", "patterns": [{ "begin": "`", "end": "`", "name": "string.template" }] }"#,
+            )
+            .unwrap();
+        builder
+            .add_from_json(
+                r#"{
+                  "scopeName": "inline.interpolation",
+                  "injectionSelector": "string.template",
+                  "injectTo": ["source.js", "source.ts"],
+                  "patterns": [{ "match": "\\$\\{\\w+\\}", "name": "meta.interpolation" }]
+                }"#,
+            )
+            .unwrap();
+        let set = builder.build().unwrap();
+
+        let js = set.find_by_scope("source.js").unwrap();
+        let tokens = js.tokenize_line("${a}`${a}`", &mut TokenizerState::default());
+        let scopes: Vec<_> = tokens.iter().map(|t| t.scopes.join(" ")).collect();
+        assert_eq!(
+            scopes,
+            [
+                "source.js",
+                "source.js string.template",
+                "source.js string.template meta.interpolation",
+                "source.js string.template"
+            ]
+        );
+
+        let injector = set.find_by_scope("inline.interpolation").unwrap();
+        let missing: Vec<_> = injector.missing_injection_targets().collect();
+        assert_eq!(missing, ["source.ts"]);
+    }
+
+    #[test]
+    fn lists_unresolved_includes() {
+        let grammar = Grammar::from_json(
+            r##"{
+              "scopeName": "source.embedding",
+              "patterns": [{ "include": "source.css" }, { "include": "#nowhere" }]
+            }"##,
+        )
+        .unwrap();
+
+        let unresolved: Vec<_> = grammar.unresolved_includes().collect();
+        assert_eq!(unresolved, vec!["source.css", "#nowhere"]);
+    }
+
+    #[test]
+    fn links_grammars_of_a_set() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder
+            .add_from_json(
+                r#"{
+                  "scopeName": "source.outer",
+                  "patterns": [{ "begin": "<", "end": ">", "name": "embedded", "patterns": [{ "include": "source.letters" }] }]
+                }"#,
+            )
+            .unwrap();
+        builder.add_from_json(LETTERS_TMLANG).unwrap();
+        let syntax_set = builder.build().unwrap();
+
+        let grammars: Vec<_> = syntax_set.grammars().collect();
+        assert_eq!(grammars[0].scope_name(), "source.outer");
+        assert_eq!(grammars[1].scope_name(), "source.letters");
+        assert_eq!(grammars[0].unresolved_includes().count(), 0);
+
+        let scopes = grammars[0].tokenize_to_scopes("<a>", &mut TokenizerState::default());
+        assert_eq!(
+            scopes[1],
+            (1..2, vec!["source.outer", "embedded", "keyword.letter"])
+        );
+    }
+
+    #[test]
+    fn finds_grammars_by_scope() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_json(LETTERS_TMLANG).unwrap();
+        builder
+            .add_from_json(r#"{ "scopeName": "source.digits", "patterns": [{ "match": "\\d", "name": "digit" }] }"#)
+            .unwrap();
+        let syntax_set = builder.build().unwrap();
+
+        let letters = syntax_set.find_by_scope("source.letters").unwrap();
+        let digits = syntax_set.find_by_scope("source.digits").unwrap();
+        assert_eq!(letters.scope_name(), "source.letters");
+        assert_eq!(digits.scope_name(), "source.digits");
+        assert!(syntax_set.find_by_scope("source").is_none());
+
+        let mut state = TokenizerState::default();
+        assert_eq!(
+            digits.tokenize_to_scopes("a1", &mut state),
+            vec![
+                (0..1, vec!["source.digits"]),
+                (1..2, vec!["source.digits", "digit"])
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_from_a_saved_state() {
+        let grammar = Grammar::from_json(
+            r#"{
+              "scopeName": "source.heredoc",
+              "patterns": [{ "begin": "<<(\\w+)", "end": "^\\1$", "name": "string" }]
+            }"#,
+        )
+        .unwrap();
+        let mut state = TokenizerState::default();
+        grammar.tokenize_line("<<EOT", &mut state);
+        let saved = state.clone();
+
+        let (tokens, after) = grammar.tokenize_from("EOF", &state);
+        assert_eq!(tokens[0].scopes, ["source.heredoc", "string"]);
+        assert_eq!(state, saved);
+        assert_eq!(after, saved);
+
+        // the end pattern still knows the delimiter of the first line
+        let (tokens, after) = grammar.tokenize_from("EOT", &state);
+        assert_eq!(tokens[0].scopes, ["source.heredoc", "string"]);
+        assert_eq!(state, saved);
+        let (tokens, _) = grammar.tokenize_from("EOT", &after);
+        assert_eq!(tokens[0].scopes, ["source.heredoc"]);
+    }
+
+    #[test]
+    fn normalizes_scope_names_when_asked() {
+        let json = r#"{
+          "scopeName": "source.sloppy",
+          "patterns": [
+            { "match": "a", "name": " keyword.letter " },
+            { "match": "b", "name": "Keyword.Letter" }
+          ]
+        }"#;
+        let scopes = |builder: &mut SyntaxSetBuilder| {
+            builder.add_from_json(json).unwrap();
+            let syntax_set = std::mem::take(builder).build().unwrap();
+            let grammar = syntax_set.grammars().next().unwrap();
+            let tokens = grammar.tokenize_line("ab", &mut TokenizerState::default());
+            tokens
+                .iter()
+                .map(|t| t.scopes[1].clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            scopes(&mut SyntaxSetBuilder::new()),
+            [" keyword.letter ", "Keyword.Letter"]
+        );
+        assert_eq!(
+            scopes(SyntaxSetBuilder::new().trim_scope_names(true)),
+            ["keyword.letter", "Keyword.Letter"]
+        );
+        assert_eq!(
+            scopes(
+                SyntaxSetBuilder::new()
+                    .trim_scope_names(true)
+                    .lowercase_scope_names(true)
+            ),
+            ["keyword.letter", "keyword.letter"]
+        );
+    }
+
+    #[test]
+    fn precompiles_regexes() {
+        let grammar = Grammar::from_json(
+            r#"{
+              "scopeName": "source.precompiled",
+              "patterns": [
+                { "match": "\\Ga" },
+                { "begin": "(<)", "end": "\\1" }
+              ]
+            }"#,
+        )
+        .unwrap();
+        assert!(grammar.precompile_regexes().is_ok());
+        assert!(grammar.precompile_regexes().is_ok());
+
+        let grammar = Grammar::from_json(
+            r#"{ "scopeName": "source.broken", "patterns": [{ "match": "a" }, { "match": "(" }] }"#,
+        )
+        .unwrap();
+        let err = grammar.precompile_regexes().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "grammar \"source.broken\" has an invalid regex"
+        );
+        // the failure is remembered, too
+        assert!(grammar.precompile_regexes().is_err());
+    }
+
+    #[cfg(feature = "rule-origins")]
+    #[test]
+    fn tells_where_rules_came_from() {
+        let grammar = Grammar::from_json(
+            r##"{
+              "scopeName": "source.origins",
+              "patterns": [{ "match": "\\d+", "name": "number" }, { "include": "#string" }],
+              "repository": {
+                "string": {
+                  "begin": "\"",
+                  "end": "\"",
+                  "name": "string",
+                  "beginCaptures": { "0": { "name": "punctuation" } },
+                  "endCaptures": { "0": { "name": "punctuation" } }
+                }
+              }
+            }"##,
+        )
+        .unwrap();
+
+        let origins = |scope| {
+            grammar
+                .rules_with_scope(scope)
+                .into_iter()
+                .map(|rule_id| grammar.rule_origin(rule_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(origins("number"), [Some("source.origins > patterns[0]")]);
+        assert_eq!(
+            origins("string"),
+            [Some("source.origins > repository.string")]
+        );
+        // both captures compile to the same rule, which keeps the first origin
+        assert_eq!(
+            origins("punctuation"),
+            [Some(
+                "source.origins > repository.string > beginCaptures > 0"
+            )]
+        );
+        assert!(origins("keyword").is_empty());
+    }
+
+    #[test]
+    fn finds_grammars_by_extension() {
+        let mut builder = SyntaxSetBuilder::new();
+        for (scope_name, file_types) in [
+            ("source.rust", r#"["rs"]"#),
+            ("source.other", r#"["rs", "Makefile"]"#),
+        ] {
+            builder
+                .add_from_json(&format!(
+                    r#"{{ "scopeName": "{scope_name}", "fileTypes": {file_types}, "patterns": [] }}"#
+                ))
+                .unwrap();
+        }
+        let syntax_set = builder.build().unwrap();
+
+        let scope_name = |extension| {
+            let grammar = syntax_set.find_by_extension(extension)?;
+            Some(grammar.scope_name().to_string())
+        };
+        assert_eq!(scope_name(".rs").as_deref(), Some("source.rust"));
+        assert_eq!(scope_name("rs").as_deref(), Some("source.rust"));
+        assert_eq!(scope_name("Makefile").as_deref(), Some("source.other"));
+        assert_eq!(scope_name("py"), None);
+    }
+
+    #[test]
+    fn keeps_metadata_unless_told_not_to() {
+        let json = r#"{
+          "scopeName": "source.rust",
+          "name": "Rust",
+          "fileTypes": ["rs"],
+          "patterns": []
+        }"#;
+        let grammar = Grammar::from_json(json).unwrap();
+        assert_eq!(grammar.name(), Some("Rust"));
+        assert_eq!(grammar.file_types(), ["rs"]);
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.keep_metadata(false).add_from_json(json).unwrap();
+        let syntax_set = builder.build().unwrap();
+        let grammar = syntax_set.find_by_scope("source.rust").unwrap();
+        assert_eq!(grammar.name(), None);
+        assert!(grammar.file_types().is_empty());
+        assert!(syntax_set.find_by_extension("rs").is_none());
+    }
+
+    #[test]
+    fn tokenizes_the_same_after_pruning() {
+        // the unused rules sort first, so pruning renumbers every regex that stays
+        let json = r##"{
+          "scopeName": "source.pruned",
+          "patterns": [{ "include": "#string" }, { "match": "\\d+", "name": "number" }],
+          "repository": {
+            "aaa": { "match": "x", "name": "unused" },
+            "abc": { "begin": "y", "end": "z" },
+            "string": { "begin": "\"", "end": "\"", "name": "string" }
+          }
+        }"##;
+        let mut builder = SyntaxSetBuilder::new();
+        builder
+            .prune_unreachable_rules(true)
+            .add_from_json(json)
+            .unwrap();
+        let syntax_set = builder.build().unwrap();
+        let pruned = syntax_set.find_by_scope("source.pruned").unwrap();
+        let grammar = Grammar::from_json(json).unwrap();
+
+        let text = "x 12 \"y z\" y";
+        assert_eq!(pruned.tokenize_file(text), grammar.tokenize_file(text));
+        assert!(!pruned.scopes().contains("unused"));
+        assert!(grammar.scopes().contains("unused"));
+    }
+
+    #[test]
+    fn finds_grammars_by_first_line() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_json(LETTERS_TMLANG).unwrap();
+        builder
+            .add_from_json(
+                r#"{ "scopeName": "source.broken", "firstLineMatch": "(", "patterns": [] }"#,
+            )
+            .unwrap();
+        builder
+            .add_from_json(
+                r#"{ "scopeName": "source.shell", "firstLineMatch": "^#!.*\\b(bash|sh)\\b", "patterns": [] }"#,
+            )
+            .unwrap();
+        let syntax_set = builder.build().unwrap();
+
+        let shell = syntax_set
+            .find_by_first_line("#!/usr/bin/env bash")
+            .unwrap();
+        assert_eq!(shell.scope_name(), "source.shell");
+        assert!(syntax_set.find_by_first_line("#!/usr/bin/python").is_none());
+    }
+
+    #[test]
+    fn rejects_duplicate_scope_names() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_json(LETTERS_TMLANG).unwrap();
+        builder.add_from_json(LETTERS_TMLANG).unwrap();
+
+        let err = builder.build().unwrap_err();
+        assert_eq!(err.to_string(), "failed to link grammars");
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "more than one grammar has the scope name \"source.letters\""
+        );
+    }
+
+    #[test]
+    fn patches_repository_rules() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder
+            .add_from_json(
+                r##"{
+                  "scopeName": "source.patched",
+                  "patterns": [{ "include": "#letter" }, { "include": "#digit" }],
+                  "repository": {
+                    "letter": { "match": "[a-", "name": "broken" },
+                    "digit": { "match": "[0-9]", "name": "digit" },
+                    "upper": { "match": "[A-Z]", "name": "upper" }
+                  }
+                }"##,
+            )
+            .unwrap();
+        builder
+            .patch_rule(
+                "source.patched",
+                "letter",
+                r##"{ "patterns": [{ "match": "[a-z]", "name": "letter" }, { "include": "#upper" }] }"##,
+            )
+            .unwrap();
+        builder.patch_rule("source.patched", "digit", "{}").unwrap();
+
+        let errors = [
+            ("source.missing", "letter", "{}"),
+            ("source.patched", "missing", "{}"),
+            ("source.patched", "letter", r##"{ "include": "#missing" }"##),
+            ("source.patched", "letter", "3"),
+        ]
+        .map(|(scope_name, key, json)| {
+            let err = builder.patch_rule(scope_name, key, json).unwrap_err();
+            let source = std::error::Error::source(&err).map(|source| source.to_string());
+            (err.to_string(), source)
+        });
+        assert_eq!(
+            errors,
+            [
+                (
+                    "no grammar with the scope name \"source.missing\" to patch".to_string(),
+                    None
+                ),
+                (
+                    "failed to patch grammar \"source.patched\"".to_string(),
+                    Some("grammar has no top level repository rule \"missing\"".to_string())
+                ),
+                (
+                    "failed to patch grammar \"source.patched\"".to_string(),
+                    Some(
+                        "patched rule \"letter\" includes #missing, which doesn't exist"
+                            .to_string()
+                    )
+                ),
+                (
+                    "failed to parse grammar".to_string(),
+                    Some("failed to deserialize json at \".\"".to_string())
+                ),
+            ]
+        );
+
+        let grammar = builder.build().unwrap().grammars().next().unwrap();
+        let tokens = grammar.tokenize_line("aZ1", &mut TokenizerState::default());
+        let scopes: Vec<_> = tokens.iter().map(|t| t.scopes.join(" ")).collect();
+        assert_eq!(
+            scopes,
+            [
+                "source.patched letter",
+                "source.patched upper",
+                "source.patched"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_invalid_grammars() {
+        let err = Grammar::from_json(r#"{ "patterns": [] }"#).unwrap_err();
+        assert_eq!(err.to_string(), "failed to parse grammar");
+    }
+}
@@ -1,6 +1,36 @@
-use crate::syntax_compiler::{compile, parse};
-
+mod grammar;
+pub mod render;
+pub(crate) mod selector;
 pub(crate) mod syntax_compiler;
+mod theme;
+pub(crate) mod tokenizer;
+
+pub use grammar::{Error, Grammar, SyntaxSet, SyntaxSetBuilder};
+pub use syntax_compiler::compile::{CompileStats, RuleId, ScopeId};
+pub use syntax_compiler::validate::ValidationWarning;
+pub use theme::{FontStyle, Rgba, Style, Theme, ThemeError};
+pub use tokenizer::{
+    OffsetMode, Position, ScopeEvent, ScopeStack, Token, TokenizerConfig, TokenizerState,
+};
+
+/// The stages of loading a grammar, separately, for benchmarks. Not a stable API.
+#[doc(hidden)]
+pub mod stages {
+    use crate::syntax_compiler::{compile, parse};
+
+    /// A grammar that has been deserialized, but not compiled.
+    #[derive(Clone)]
+    pub struct Parsed(parse::SyntaxDefinition);
+
+    pub fn parse(json: &str) -> Result<Parsed, impl std::error::Error> {
+        parse::SyntaxDefinition::from_json(json).map(Parsed)
+    }
+
+    // the compiled grammar is only returned to be dropped outside the measurement
+    pub fn compile(parsed: Parsed) -> Result<impl Sized, impl std::error::Error> {
+        compile::SyntaxDefinition::compile(parsed.0, &mut compile::ScopeInterner::default())
+    }
+}
 
 const ABC_TMLANG: &str = r##"{
   "scopeName": "source.abc",
@@ -62,11 +92,70 @@ x
 (
 a";
 
-fn parse_line(syntax: &compile::SyntaxDefinition, line: &str) {}
-
+/// Highlights a small program with a toy grammar and prints every token with its
+/// scopes, showing the whole flow from a grammar to scoped tokens.
 pub fn test() {
-    let parsed = parse::SyntaxDefinition::from_json(ABC_TMLANG).unwrap();
-    let compiled = compile::SyntaxDefinition::compile(parsed).unwrap();
+    let grammar = Grammar::from_json(ABC_TMLANG).unwrap();
+    println!("{}", grammar.dump_summary());
 
-    println!("{:?}", compiled);
+    for (line, tokens) in ABC_PROGRAM.lines().zip(grammar.tokenize_file(ABC_PROGRAM)) {
+        println!("{line}");
+        for token in tokens {
+            println!("  {:?}: {}", &line[token.range], token.scopes.join(" "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_nested_paren_expressions() {
+        let grammar = Grammar::from_json(ABC_TMLANG).unwrap();
+        let lines = grammar.tokenize_file(ABC_PROGRAM);
+
+        // `c` is two paren expressions deep
+        let c = lines[7].iter().find(|token| token.range == (8..9)).unwrap();
+        assert_eq!(
+            c.scopes,
+            [
+                "source.abc",
+                "expression.group",
+                "expression.group",
+                "keyword.letter"
+            ]
+        );
+
+        // the last paren is never closed, so the group carries on to the end
+        let a = &lines[12][0];
+        assert_eq!(
+            a.scopes,
+            ["source.abc", "expression.group", "keyword.letter"]
+        );
+    }
+
+    #[test]
+    fn reports_where_paren_expressions_start_and_end() {
+        let grammar = Grammar::from_json(ABC_TMLANG).unwrap();
+        let events = grammar.tokenize_events(ABC_PROGRAM);
+
+        let group = || "expression.group".to_string();
+        let push = |line, offset| ScopeEvent::Push(group(), Position { line, offset });
+        let pop = |line, offset| ScopeEvent::Pop(group(), Position { line, offset });
+        assert_eq!(
+            events,
+            [
+                push(1, 0),
+                pop(3, 1),
+                push(5, 0),
+                push(6, 4),
+                pop(9, 5),
+                pop(10, 1),
+                push(11, 0),
+                // never closed, so it ends with the text
+                pop(12, 1),
+            ]
+        );
+    }
 }
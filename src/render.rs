@@ -0,0 +1,237 @@
+//! Rendering of tokenized lines into highlighted text.
+//!
+//! Lines are sliced by token ranges, so the tokens need byte ranges, the
+//! default [`OffsetMode`](crate::OffsetMode).
+
+use crate::theme::{Rgba, Style, Theme};
+use crate::tokenizer::Token;
+use std::fmt::Write;
+
+/// Renders a tokenized line with 24-bit ANSI escape codes.
+///
+/// Every token gets the style `theme` resolves for its scopes; text not covered
+/// by any token gets the theme's default style. Translucent colors are drawn
+/// over the theme's background. The output ends with a reset, so that the
+/// style doesn't leak into whatever is printed next.
+pub fn to_ansi(tokens: &[Token], theme: &Theme, line: &str) -> String {
+    render_ansi(tokens, theme, line, Style::to_ansi_truecolor)
+}
+
+/// Like [`to_ansi`], for terminals that only support the 256-color palette.
+pub fn to_ansi_256(tokens: &[Token], theme: &Theme, line: &str) -> String {
+    render_ansi(tokens, theme, line, Style::to_ansi_256)
+}
+
+fn render_ansi(tokens: &[Token], theme: &Theme, line: &str, escape: fn(Style) -> String) -> String {
+    let background = theme.default_style().background;
+    let mut output = String::with_capacity(line.len() * 2);
+    let mut push_styled = |style: Style, text: &str| {
+        output.push_str(&escape(style.over(background)));
+        output.push_str(text);
+    };
+    let mut empty = true;
+    for_each_span(tokens, line, |scopes, text| {
+        let style = match scopes {
+            Some(scopes) => theme.style_for(scopes),
+            None => theme.default_style(),
+        };
+        push_styled(style, text);
+        empty = false;
+    });
+    if empty {
+        push_styled(theme.default_style(), "");
+    }
+
+    output.push_str("\x1b[0m");
+    output
+}
+
+/// Renders a tokenized line as HTML, with inline styles from `theme`.
+///
+/// Text is HTML-escaped; tokens with no style are emitted without a span.
+pub fn to_html(tokens: &[Token], theme: &Theme, line: &str) -> String {
+    let mut output = String::with_capacity(line.len() * 4);
+    for_each_span(tokens, line, |scopes, text| {
+        let style = match scopes {
+            Some(scopes) => theme.style_for(scopes),
+            None => theme.default_style(),
+        };
+        let css = inline_css(style);
+        push_span(
+            &mut output,
+            (!css.is_empty()).then_some(("style", css)),
+            text,
+        );
+    });
+    output
+}
+
+/// Renders a tokenized line as HTML, with CSS classes for an external stylesheet.
+///
+/// The classes are the segments of the innermost scope, e.g. `string.quoted`
+/// becomes `class="string quoted"`.
+pub fn to_html_classes(tokens: &[Token], line: &str) -> String {
+    let mut output = String::with_capacity(line.len() * 4);
+    for_each_span(tokens, line, |scopes, text| {
+        let classes = scopes
+            .and_then(|scopes| scopes.last())
+            .map(|scope| scope.split('.').collect::<Vec<_>>().join(" "));
+        push_span(&mut output, classes.map(|classes| ("class", classes)), text);
+    });
+    output
+}
+
+// calls `f` with the scopes and text of every non-empty token, and with None for
+// text that no token covers
+fn for_each_span(tokens: &[Token], line: &str, mut f: impl FnMut(Option<&[String]>, &str)) {
+    let mut last_end = 0;
+    for token in tokens {
+        if token.range.is_empty() {
+            continue;
+        }
+        if token.range.start > last_end {
+            f(None, &line[last_end..token.range.start]);
+        }
+        f(Some(&token.scopes), token.text(line));
+        last_end = token.range.end;
+    }
+    if last_end < line.len() {
+        f(None, &line[last_end..]);
+    }
+}
+
+fn inline_css(style: Style) -> String {
+    let mut css = Vec::new();
+    if let Some(color) = style.foreground {
+        css.push(format!("color:{}", hex(color)));
+    }
+    if let Some(color) = style.background {
+        css.push(format!("background-color:{}", hex(color)));
+    }
+    if style.font_style.bold {
+        css.push("font-weight:bold".to_string());
+    }
+    if style.font_style.italic {
+        css.push("font-style:italic".to_string());
+    }
+    if style.font_style.underline {
+        css.push("text-decoration:underline".to_string());
+    }
+    css.join(";")
+}
+
+fn hex(Rgba { r, g, b, a }: Rgba) -> String {
+    if a == 0xff {
+        format!("#{r:02x}{g:02x}{b:02x}")
+    } else {
+        format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+    }
+}
+
+fn push_span(output: &mut String, attribute: Option<(&str, String)>, text: &str) {
+    let Some((name, value)) = attribute else {
+        push_escaped(output, text);
+        return;
+    };
+
+    write!(output, "<span {name}=\"").unwrap();
+    push_escaped(output, &value);
+    output.push_str("\">");
+    push_escaped(output, text);
+    output.push_str("</span>");
+}
+
+fn push_escaped(output: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            c => output.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THEME_JSON: &str = r##"{
+      "colors": { "editor.foreground": "#ffffff" },
+      "tokenColors": [
+        { "scope": "keyword", "settings": { "foreground": "#ff0000", "background": "#000000", "fontStyle": "bold italic" } }
+      ]
+    }"##;
+
+    fn token(range: std::ops::Range<usize>, scopes: &[&str]) -> Token {
+        Token {
+            range,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn renders_styled_tokens() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+        let tokens = [token(0..2, &[]), token(2..3, &["keyword.letter"])];
+
+        assert_eq!(
+            to_ansi(&tokens, &theme, "x a"),
+            "\x1b[0;38;2;255;255;255mx \x1b[0;38;2;255;0;0;48;2;0;0;0;1;3ma\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn renders_unmatched_text_with_default_style() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+
+        assert_eq!(
+            to_ansi(&[], &theme, "xyz"),
+            "\x1b[0;38;2;255;255;255mxyz\x1b[0m"
+        );
+        assert_eq!(
+            to_ansi(&[token(0..0, &["keyword"])], &theme, ""),
+            "\x1b[0;38;2;255;255;255m\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn renders_256_colors() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+        let tokens = [token(0..2, &[]), token(2..3, &["keyword.letter"])];
+
+        assert_eq!(
+            to_ansi_256(&tokens, &theme, "x a"),
+            "\x1b[0;38;5;231mx \x1b[0;38;5;196;48;5;16;1;3ma\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn renders_inline_styled_html() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+        let tokens = [token(0..4, &[]), token(4..5, &["keyword.letter"])];
+
+        assert_eq!(
+            to_html(&tokens, &theme, "<&\"'a"),
+            concat!(
+                "<span style=\"color:#ffffff\">&lt;&amp;&quot;&#39;</span>",
+                "<span style=\"color:#ff0000;background-color:#000000;font-weight:bold;font-style:italic\">a</span>",
+            )
+        );
+    }
+
+    #[test]
+    fn renders_html_classes() {
+        let tokens = [
+            token(0..1, &[]),
+            token(1..4, &["source.js", "string.quoted"]),
+        ];
+
+        assert_eq!(
+            to_html_classes(&tokens, "x'a'"),
+            "x<span class=\"string quoted\">&#39;a&#39;</span>"
+        );
+    }
+}
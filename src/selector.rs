@@ -0,0 +1,365 @@
+// Scope selectors, as used by injections and themes, e.g. `L:source.js -comment`.
+//
+// The syntax closely follows
+// https://github.com/microsoft/vscode-textmate/blob/f03a6a8790af81372d0e81facae75554ec5e97ef/src/matcher.ts
+// - `a b` matches scope stacks that have `a` with `b` somewhere inside it;
+// - `-a` matches scope stacks that don't match `a`;
+// - `a, b` and `a | b` match either;
+// - `(...)` groups;
+// - `L:`/`R:` in front of a top level alternative sets the injection priority.
+// As in vscode-textmate, parsing never fails: whatever can't be parsed is skipped.
+
+use serde_derive::{Deserialize, Serialize};
+
+// serialized as its source, which is parsed again when deserializing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub(crate) struct Selector {
+    source: String,
+    alternatives: Vec<Alternative>,
+}
+
+// Where injected patterns go relative to the patterns they are injected into.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub(crate) enum Priority {
+    // L:, before the patterns
+    Left,
+    #[default]
+    Normal,
+    // R:, after the patterns
+    Right,
+}
+
+// Ranks competing selectors, higher is better: a selector that matches a scope
+// deeper in the stack wins, then the one that names more of the scope
+// (`string.quoted` over `string`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub(crate) struct MatchScore {
+    // 1-based position of the innermost matched scope, 0 for purely negative selectors
+    pub(crate) depth: usize,
+    // number of dot-separated segments in the matched selector scopes
+    pub(crate) segments: usize,
+    pub(crate) priority: Priority,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Alternative {
+    priority: Priority,
+    expression: Expression,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expression {
+    // scopes that have to appear in the stack in this order, not necessarily adjacent
+    Path(Vec<String>),
+    Not(Box<Expression>),
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+}
+
+impl Selector {
+    pub(crate) fn parse(source: &str) -> Self {
+        let mut parser = Parser {
+            tokens: tokenize(source),
+            pos: 0,
+        };
+
+        let mut alternatives = Vec::new();
+        while parser.peek().is_some() {
+            let priority = match parser.peek() {
+                Some("L:") => Priority::Left,
+                Some("R:") => Priority::Right,
+                _ => Priority::Normal,
+            };
+            if priority != Priority::Normal {
+                parser.next();
+            }
+
+            if let Some(expression) = parser.parse_conjunction() {
+                alternatives.push(Alternative {
+                    priority,
+                    expression,
+                });
+            }
+
+            match parser.peek() {
+                Some(",") => {
+                    parser.next();
+                }
+                // skip over whatever made the conjunction stop, e.g. a stray `)`
+                Some(_) => {
+                    parser.next();
+                }
+                None => {}
+            }
+        }
+
+        Self {
+            source: source.to_string(),
+            alternatives,
+        }
+    }
+
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Scopes are outermost first. Returns the best score of the alternatives that
+    // match, or None if none do.
+    pub(crate) fn matches<S: AsRef<str>>(&self, scope_stack: &[S]) -> Option<MatchScore> {
+        self.alternatives
+            .iter()
+            .filter_map(|alternative| {
+                let score = alternative.expression.matches(scope_stack)?;
+                Some(MatchScore {
+                    priority: alternative.priority,
+                    ..score
+                })
+            })
+            .max()
+    }
+}
+
+impl From<String> for Selector {
+    fn from(source: String) -> Self {
+        Self::parse(&source)
+    }
+}
+
+impl From<Selector> for String {
+    fn from(selector: Selector) -> Self {
+        selector.source
+    }
+}
+
+impl Expression {
+    fn matches<S: AsRef<str>>(&self, scope_stack: &[S]) -> Option<MatchScore> {
+        match self {
+            Expression::Path(path) => match_path(path, scope_stack),
+            Expression::Not(expression) => match expression.matches(scope_stack) {
+                Some(_) => None,
+                None => Some(MatchScore::default()),
+            },
+            Expression::And(expressions) => {
+                let mut best = MatchScore::default();
+                for expression in expressions {
+                    best = best.max(expression.matches(scope_stack)?);
+                }
+                Some(best)
+            }
+            Expression::Or(expressions) => expressions
+                .iter()
+                .filter_map(|expression| expression.matches(scope_stack))
+                .max(),
+        }
+    }
+}
+
+// Matches the path from its last scope backwards, so that the last scope is
+// matched as deep in the stack as possible.
+fn match_path<S: AsRef<str>>(path: &[String], scope_stack: &[S]) -> Option<MatchScore> {
+    let mut remaining = scope_stack.len();
+    let mut depth = None;
+
+    for selector_scope in path.iter().rev() {
+        let found = scope_stack[..remaining]
+            .iter()
+            .rposition(|scope| scope_matches(selector_scope, scope.as_ref()))?;
+        depth.get_or_insert(found + 1);
+        remaining = found;
+    }
+
+    Some(MatchScore {
+        depth: depth.unwrap_or(0),
+        segments: path.iter().map(|scope| scope.split('.').count()).sum(),
+        priority: Priority::Normal,
+    })
+}
+
+// `string.quoted` matches `string.quoted` and `string.quoted.double`,
+// but not `string.quotedx`
+pub(crate) fn scope_matches(selector_scope: &str, scope: &str) -> bool {
+    scope
+        .strip_prefix(selector_scope)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    // operands following each other, all of which have to match
+    fn parse_conjunction(&mut self) -> Option<Expression> {
+        let mut operands = Vec::new();
+        while let Some(operand) = self.parse_operand() {
+            operands.push(operand);
+        }
+
+        match operands.len() {
+            0 => None,
+            1 => operands.pop(),
+            _ => Some(Expression::And(operands)),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Option<Expression> {
+        match self.peek()? {
+            "-" => {
+                self.next();
+                let operand = self.parse_operand()?;
+                Some(Expression::Not(Box::new(operand)))
+            }
+            "(" => {
+                self.next();
+                let expression = self.parse_inner_expression();
+                if self.peek() == Some(")") {
+                    self.next();
+                }
+                expression
+            }
+            token if is_scope(token) => {
+                let mut path = Vec::new();
+                while let Some(token) = self.peek().filter(|token| is_scope(token)) {
+                    path.push(token.to_string());
+                    self.next();
+                }
+                Some(Expression::Path(path))
+            }
+            _ => None,
+        }
+    }
+
+    // alternatives inside parentheses, which can't have a priority
+    fn parse_inner_expression(&mut self) -> Option<Expression> {
+        let mut alternatives = Vec::new();
+        loop {
+            if let Some(conjunction) = self.parse_conjunction() {
+                alternatives.push(conjunction);
+            }
+            match self.peek() {
+                Some("," | "|") => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+
+        match alternatives.len() {
+            0 => None,
+            1 => alternatives.pop(),
+            _ => Some(Expression::Or(alternatives)),
+        }
+    }
+}
+
+fn is_scope(token: &str) -> bool {
+    !matches!(token, "," | "|" | "-" | "(" | ")" | "L:" | "R:")
+}
+
+// splits into `L:`/`R:` prefixes, punctuation and scope names; scope names can
+// contain dashes, but not start with one
+fn tokenize(source: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+
+        let len = if rest.starts_with("L:") || rest.starts_with("R:") {
+            2
+        } else if rest.starts_with([',', '|', '-', '(', ')']) {
+            1
+        } else {
+            rest.find(|c: char| c.is_whitespace() || matches!(c, ',' | '|' | '(' | ')'))
+                .unwrap_or(rest.len())
+        };
+
+        tokens.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(selector: &str, scopes: &str) -> Option<MatchScore> {
+        let scopes: Vec<_> = scopes.split_whitespace().collect();
+        Selector::parse(selector).matches(&scopes)
+    }
+
+    #[test]
+    fn matches_scope_prefixes() {
+        assert!(score("string", "source.js string.quoted.double").is_some());
+        assert!(score("string.quoted", "source.js string.quoted.double").is_some());
+        assert!(score("string.quote", "source.js string.quoted.double").is_none());
+        assert!(score("comment", "source.js string.quoted.double").is_none());
+    }
+
+    #[test]
+    fn matches_descendants_in_order() {
+        assert!(
+            score(
+                "text.html meta.embedded",
+                "text.html.basic meta.embedded.block"
+            )
+            .is_some()
+        );
+        assert!(score("text.html meta.embedded", "text.html.basic x meta.embedded").is_some());
+        assert!(score("meta.embedded text.html", "text.html.basic meta.embedded").is_none());
+    }
+
+    #[test]
+    fn matches_exclusions_and_alternatives() {
+        assert!(score("source.js -comment", "source.js string").is_some());
+        assert!(score("source.js -comment", "source.js comment.line").is_none());
+        assert!(score("-comment", "source.js").is_some());
+        assert!(score("comment, string", "source.js string").is_some());
+        assert!(score("source.js (comment | string)", "source.js string").is_some());
+        assert!(score("source.js -(comment | string)", "source.js string").is_none());
+    }
+
+    #[test]
+    fn parses_priorities() {
+        let selector = Selector::parse("L:source.js -comment, R:text.html, source.ts");
+        let priorities: Vec<_> = selector.alternatives.iter().map(|a| a.priority).collect();
+        assert_eq!(
+            priorities,
+            vec![Priority::Left, Priority::Right, Priority::Normal]
+        );
+
+        assert_eq!(
+            score("L:source.js -comment", "source.js").map(|score| score.priority),
+            Some(Priority::Left)
+        );
+    }
+
+    #[test]
+    fn prefers_deeper_and_more_specific_matches() {
+        let scopes = "source.js string.quoted.double";
+        assert!(score("string", scopes) > score("source", scopes));
+        assert!(score("string.quoted", scopes) > score("string", scopes));
+        assert!(score("source string", scopes) > score("string", scopes));
+    }
+
+    #[test]
+    fn skips_garbage() {
+        assert!(score("", "source.js").is_none());
+        assert!(score(") string", "string").is_some());
+        assert!(score("(string", "string").is_some());
+    }
+}
@@ -1,2 +1,5 @@
 pub(crate) mod compile;
+pub(crate) mod engine;
+pub(crate) mod link;
 pub(crate) mod parse;
+pub(crate) mod validate;
@@ -0,0 +1,181 @@
+// The regex engine behind `CompiledRegex`, chosen with the `backend-onig` and
+// `backend-fancy` features. Oniguruma wins if both are enabled, since it's what
+// grammars are written for.
+use smallvec::SmallVec;
+use std::fmt;
+
+#[cfg(not(any(feature = "backend-onig", feature = "backend-fancy")))]
+compile_error!("enable a regex backend, `backend-onig` or `backend-fancy`");
+
+#[cfg(feature = "backend-onig")]
+pub(crate) type Regex = Onig;
+#[cfg(all(feature = "backend-fancy", not(feature = "backend-onig")))]
+pub(crate) type Regex = Fancy;
+
+pub(crate) trait RegexEngine: Sized + Send + Sync + fmt::Debug {
+    // Err is the engine's explanation of why it rejects the pattern
+    fn new(pattern: &str) -> Result<Self, String>;
+
+    // The leftmost match that starts at or after `offset`, with where every group
+    // matched; `\G` matches at `offset`. Err if the match took more than
+    // `max_steps` backtracking steps, or whatever limit the engine has.
+    fn search_from(
+        &self,
+        text: &str,
+        offset: usize,
+        max_steps: Option<u32>,
+    ) -> Result<Option<Region>, LimitExceeded>;
+
+    // the indices of the groups with each name; a name can be given to several
+    fn named_groups(&self) -> Vec<(String, Vec<usize>)>;
+}
+
+#[derive(Debug)]
+pub(crate) struct LimitExceeded;
+
+// Where the groups of a match are, the whole match being group 0; groups that
+// didn't participate are None. Most patterns have few groups, so they are inline.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Region(SmallVec<[Option<(usize, usize)>; 8]>);
+
+impl Region {
+    pub(crate) fn pos(&self, group: usize) -> Option<(usize, usize)> {
+        self.0.get(group).copied().flatten()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(feature = "backend-onig")]
+#[derive(Debug)]
+pub(crate) struct Onig(onig::Regex);
+
+#[cfg(feature = "backend-onig")]
+impl RegexEngine for Onig {
+    fn new(pattern: &str) -> Result<Self, String> {
+        // vscode-textmate compiles with ONIG_OPTION_CAPTURE_GROUP, otherwise plain
+        // groups stop capturing as soon as a pattern has a named group
+        onig::Regex::with_options(
+            pattern,
+            onig::RegexOptions::REGEX_OPTION_CAPTURE_GROUP,
+            onig::Syntax::default(),
+        )
+        .map(Onig)
+        .map_err(|e| e.description().to_string())
+    }
+
+    fn search_from(
+        &self,
+        text: &str,
+        offset: usize,
+        max_steps: Option<u32>,
+    ) -> Result<Option<Region>, LimitExceeded> {
+        use onig::{MatchParam, SearchOptions};
+
+        let mut region = onig::Region::new();
+        let options = SearchOptions::SEARCH_OPTION_NONE;
+        let found = match max_steps {
+            Some(max_steps) => {
+                let mut match_param = MatchParam::default();
+                match_param.set_retry_limit_in_match(max_steps);
+                let found = self.0.search_with_param(
+                    text,
+                    offset,
+                    text.len(),
+                    options,
+                    Some(&mut region),
+                    match_param,
+                );
+                found.map_err(|_| LimitExceeded)?
+            }
+            None => {
+                self.0
+                    .search_with_options(text, offset, text.len(), options, Some(&mut region))
+            }
+        };
+
+        Ok(found.map(|_| Region((0..region.len()).map(|group| region.pos(group)).collect())))
+    }
+
+    fn named_groups(&self) -> Vec<(String, Vec<usize>)> {
+        let mut named = Vec::new();
+        self.0.foreach_name(|name, groups| {
+            let groups = groups.iter().map(|&group| group as usize).collect();
+            named.push((name.to_string(), groups));
+            true
+        });
+        named
+    }
+}
+
+#[cfg(all(feature = "backend-fancy", not(feature = "backend-onig")))]
+#[derive(Debug)]
+pub(crate) struct Fancy(fancy_regex::Regex);
+
+#[cfg(all(feature = "backend-fancy", not(feature = "backend-onig")))]
+impl RegexEngine for Fancy {
+    fn new(pattern: &str) -> Result<Self, String> {
+        fancy_regex::Regex::new(pattern)
+            .map(Fancy)
+            .map_err(|e| e.to_string())
+    }
+
+    // the backtracking limit is set when the pattern is compiled, so `max_steps`
+    // is left to fancy-regex's default
+    fn search_from(
+        &self,
+        text: &str,
+        offset: usize,
+        _max_steps: Option<u32>,
+    ) -> Result<Option<Region>, LimitExceeded> {
+        let captures = self
+            .0
+            .captures_from_pos(text, offset)
+            .map_err(|_| LimitExceeded)?;
+        Ok(captures.map(|captures| {
+            let groups = captures.iter();
+            Region(
+                groups
+                    .map(|group| group.map(|group| (group.start(), group.end())))
+                    .collect(),
+            )
+        }))
+    }
+
+    fn named_groups(&self) -> Vec<(String, Vec<usize>)> {
+        let names = self.0.capture_names().enumerate();
+        names
+            .filter_map(|(group, name)| Some((name?.to_string(), vec![group])))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_from_an_offset() {
+        let regex = Regex::new(r"(?<letter>a)(b)?|\Gc").unwrap();
+
+        let region = regex.search_from("xacab", 2, None).unwrap().unwrap();
+        assert_eq!(region.pos(0), Some((2, 3)));
+        assert_eq!(region.pos(1), None);
+
+        let region = regex.search_from("xacab", 1, None).unwrap().unwrap();
+        assert_eq!(region.pos(0), Some((1, 2)));
+        assert_eq!(region.pos(1), Some((1, 2)));
+        assert_eq!(region.pos(2), None);
+        assert_eq!(region.len(), 3);
+
+        assert_eq!(regex.search_from("xacab", 5, None).unwrap(), None);
+        assert_eq!(regex.named_groups(), [("letter".to_string(), vec![1])]);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(Regex::new("(unclosed").is_err());
+    }
+}
@@ -0,0 +1,448 @@
+use crate::syntax_compiler::compile::{
+    DroppedReference, Reference, RepositoryId, RepositoryStack, RuleId, RuleIdOrReference,
+    ScopeName, SyntaxId, SyntaxSet,
+};
+use std::collections::HashSet;
+
+impl SyntaxSet {
+    // Rewrites every reference into the rule id it points to, so that the tokenizer
+    // never has to resolve anything. As in TextMate, references that don't resolve
+    // are not an error, the pattern is simply dropped (and remembered in
+    // `dropped_references`).
+    pub(crate) fn link(&mut self) {
+        // resolution needs the whole set, so patterns are resolved first and
+        // written back afterwards
+        let mut linked = Vec::new();
+        let mut dropped = Vec::new();
+        let mut included_keys = HashSet::new();
+
+        for (syntax_idx, syntax) in self.syntaxes.iter().enumerate() {
+            let syntax_id = SyntaxId::from_idx(syntax_idx);
+
+            for (rule_idx, rule) in syntax.rules.iter().enumerate() {
+                let Some(patterns) = rule.patterns() else {
+                    continue;
+                };
+                let repository_stack = rule.repository_stack();
+
+                let rule_id = RuleId::from_idx(rule_idx);
+                let patterns: Vec<_> = patterns
+                    .iter()
+                    .filter_map(|pattern| match pattern {
+                        RuleIdOrReference::Reference(reference) => {
+                            let resolved = self.resolve(
+                                syntax_id,
+                                repository_stack,
+                                reference,
+                                &mut included_keys,
+                            );
+                            if resolved.is_none() {
+                                dropped.push(DroppedReference {
+                                    syntax_id,
+                                    rule_id,
+                                    reference: reference.clone(),
+                                });
+                            }
+                            // rules that match nothing resolve, but are dropped all the same
+                            resolved.flatten()
+                        }
+                        linked => Some(linked.clone()),
+                    })
+                    .collect();
+
+                linked.push((syntax_id, rule_id, patterns));
+            }
+        }
+
+        for (syntax_id, rule_id, patterns) in linked {
+            let rule = &mut self.syntaxes[syntax_id.to_idx()].rules[rule_id.to_idx()];
+            if let Some(target) = rule.patterns_mut() {
+                *target = patterns;
+            }
+        }
+        self.dropped_references.extend(dropped);
+        self.included_keys.extend(included_keys);
+
+        self.link_injectors();
+    }
+
+    // Drops the rules that can't be reached from the root or the injections of any
+    // grammar of the set, e.g. repository rules nothing includes, or only
+    // includes from patterns that were dropped, and the regexes only they used.
+    // Needs a linked set, and leaves rule ids dense.
+    pub(crate) fn prune_unreachable(&mut self) {
+        let mut reachable: Vec<Vec<bool>> = self
+            .syntaxes
+            .iter()
+            .map(|syntax| vec![false; syntax.rules.len()])
+            .collect();
+        let mut queue = Vec::new();
+        for (syntax_idx, syntax) in self.syntaxes.iter().enumerate() {
+            let syntax_id = SyntaxId::from_idx(syntax_idx);
+            // any grammar can be tokenized, not just the ones included by others
+            queue.push((syntax_id, syntax.root_rule_id()));
+            queue.extend(
+                syntax
+                    .injections
+                    .iter()
+                    .map(|(_, rule_id)| (syntax_id, *rule_id)),
+            );
+        }
+
+        while let Some((syntax_id, rule_id)) = queue.pop() {
+            let seen = &mut reachable[syntax_id.to_idx()][rule_id.to_idx()];
+            if *seen {
+                continue;
+            }
+            *seen = true;
+
+            let rule = self.syntax(syntax_id).rule(rule_id);
+            for pattern in rule.patterns().into_iter().flatten() {
+                match pattern {
+                    RuleIdOrReference::RuleId(rule_id) => queue.push((syntax_id, *rule_id)),
+                    RuleIdOrReference::ForeignRuleId(syntax_id, rule_id) => {
+                        queue.push((*syntax_id, *rule_id))
+                    }
+                    // every root is reachable anyway, and references are resolved by now
+                    RuleIdOrReference::Base | RuleIdOrReference::Reference(_) => {}
+                }
+            }
+            queue.extend(rule.captured_rules().map(|rule_id| (syntax_id, rule_id)));
+        }
+
+        let new_ids: Vec<_> = self
+            .syntaxes
+            .iter_mut()
+            .zip(&reachable)
+            .map(|(syntax, reachable)| syntax.retain_rules(reachable))
+            .collect();
+
+        // rules of other grammars can only be renumbered once all of them are
+        let patterns = self
+            .syntaxes
+            .iter_mut()
+            .flat_map(|syntax| &mut syntax.rules)
+            .filter_map(|rule| rule.patterns_mut());
+        for pattern in patterns.flatten() {
+            if let RuleIdOrReference::ForeignRuleId(syntax_id, rule_id) = pattern {
+                *rule_id = new_ids[syntax_id.to_idx()][rule_id.to_idx()]
+                    .expect("rules included by a reachable rule are reachable");
+            }
+        }
+        self.dropped_references.retain_mut(|dropped| {
+            let new_id = new_ids[dropped.syntax_id.to_idx()][dropped.rule_id.to_idx()];
+            dropped.rule_id = new_id.unwrap_or(dropped.rule_id);
+            new_id.is_some()
+        });
+    }
+
+    // registers injector grammars with the grammars they inject into
+    fn link_injectors(&mut self) {
+        let mut injectors = vec![Vec::new(); self.syntaxes.len()];
+        let mut missing = Vec::new();
+
+        for (syntax_idx, syntax) in self.syntaxes.iter().enumerate() {
+            let Some(injector) = &syntax.injector else {
+                continue;
+            };
+            let syntax_id = SyntaxId::from_idx(syntax_idx);
+            for target in &injector.inject_to {
+                match self.find_syntax(target) {
+                    Some(target_id) => injectors[target_id.to_idx()].push(syntax_id),
+                    // as with includes, the grammar might simply not be loaded
+                    None => missing.push((syntax_id, target.clone())),
+                }
+            }
+        }
+
+        self.injectors = injectors;
+        self.missing_injection_targets = missing;
+    }
+
+    fn resolve(
+        &self,
+        syntax_id: SyntaxId,
+        repository_stack: &RepositoryStack,
+        reference: &Reference,
+        included_keys: &mut HashSet<(SyntaxId, RepositoryId, ScopeName)>,
+    ) -> Option<Option<RuleIdOrReference>> {
+        // None if the reference points nowhere, Some(None) if it points to a rule
+        // that matches nothing and was compacted away; repository keys that
+        // resolve are recorded in `included_keys`
+        let syntax = self.syntax(syntax_id);
+
+        match reference {
+            Reference::Self_ => Some(Some(RuleIdOrReference::RuleId(syntax.root_rule_id()))),
+            // $base is the grammar that is being tokenized, which is this one
+            // only as long as it isn't embedded into another grammar
+            Reference::Base => Some(Some(RuleIdOrReference::Base)),
+            Reference::Relative { rule } => {
+                let (repository_id, rule_id) =
+                    syntax.find_repository_rule(repository_stack, rule)?;
+                included_keys.insert((syntax_id, repository_id, rule.clone()));
+                Some(rule_id.map(RuleIdOrReference::RuleId))
+            }
+            Reference::TopLevel { scope } => {
+                let target_id = self.find_syntax(scope)?;
+                let target = self.syntax(target_id);
+                Some(Some(rule_in(syntax_id, target_id, target.root_rule_id())))
+            }
+            Reference::TopLevelRepository { scope, rule } => {
+                let target_id = self.find_syntax(scope)?;
+                let target = self.syntax(target_id);
+                // only the target's top level repository is visible from outside
+                let root_stack = target.rule(target.root_rule_id()).repository_stack();
+                let (repository_id, rule_id) = target.find_repository_rule(root_stack, rule)?;
+                included_keys.insert((target_id, repository_id, rule.clone()));
+                Some(rule_id.map(|rule_id| rule_in(syntax_id, target_id, rule_id)))
+            }
+        }
+    }
+}
+
+// grammars can refer to themselves by scope name, which doesn't need a foreign id
+fn rule_in(from: SyntaxId, target: SyntaxId, rule_id: RuleId) -> RuleIdOrReference {
+    if from == target {
+        RuleIdOrReference::RuleId(rule_id)
+    } else {
+        RuleIdOrReference::ForeignRuleId(target, rule_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_compiler::compile::{Rule, ScopeInterner, ScopeName, SyntaxDefinition};
+    use crate::syntax_compiler::parse;
+
+    fn link(json: &str) -> SyntaxSet {
+        link_all(&[json])
+    }
+
+    fn link_all(jsons: &[&str]) -> SyntaxSet {
+        let mut scopes = ScopeInterner::default();
+        let syntaxes = jsons
+            .iter()
+            .map(|json| {
+                let parsed = parse::SyntaxDefinition::from_json(json).unwrap();
+                SyntaxDefinition::compile(parsed, &mut scopes).unwrap()
+            })
+            .collect();
+        let mut syntax_set = SyntaxSet::new(syntaxes, scopes).unwrap();
+        syntax_set.link();
+        syntax_set
+    }
+
+    fn linked_patterns(syntax: &SyntaxDefinition, rule_id: RuleId) -> Vec<RuleId> {
+        syntax
+            .rule(rule_id)
+            .patterns()
+            .unwrap()
+            .iter()
+            .map(|pattern| match pattern {
+                RuleIdOrReference::RuleId(rule_id) => *rule_id,
+                other => panic!("expected a local rule, got {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resolves_self_and_base() {
+        let syntax_set = link(
+            r#"{
+              "scopeName": "source.self",
+              "patterns": [{
+                "begin": "\\(",
+                "end": "\\)",
+                "patterns": [{ "include": "$self" }, { "include": "$base" }]
+              }]
+            }"#,
+        );
+        let syntax = &syntax_set.syntaxes[0];
+
+        let root = syntax.root_rule_id();
+        let [paren] = linked_patterns(syntax, root)[..] else {
+            panic!("expected a single root pattern");
+        };
+        assert_eq!(
+            syntax.rule(paren).patterns().unwrap(),
+            &[RuleIdOrReference::RuleId(root), RuleIdOrReference::Base]
+        );
+    }
+
+    #[test]
+    fn resolves_relative_references_through_repository_stack() {
+        let syntax_set = link(
+            r##"{
+              "scopeName": "source.nested",
+              "patterns": [{ "include": "#outer" }],
+              "repository": {
+                "outer": {
+                  "patterns": [{ "include": "#inner" }, { "include": "#letter" }],
+                  "repository": {
+                    "letter": { "match": "b", "name": "inner.letter" }
+                  }
+                },
+                "inner": { "match": "c" },
+                "letter": { "match": "a", "name": "outer.letter" }
+              }
+            }"##,
+        );
+        let syntax = &syntax_set.syntaxes[0];
+
+        let [outer] = linked_patterns(syntax, syntax.root_rule_id())[..] else {
+            panic!("expected a single root pattern");
+        };
+        let names: Vec<_> = linked_patterns(syntax, outer)
+            .into_iter()
+            .map(|rule_id| {
+                let name = syntax.rule(rule_id).name();
+                name.map(|name| syntax_set.scopes.resolve(name))
+            })
+            .collect();
+        assert_eq!(names, vec![None, Some("inner.letter")]);
+    }
+
+    #[test]
+    fn drops_missing_references() {
+        let syntax_set = link(
+            r##"{
+              "scopeName": "source.missing",
+              "patterns": [
+                { "include": "#nowhere" },
+                { "include": "source.other" },
+                { "include": "source.other#rule" },
+                { "match": "a" }
+              ]
+            }"##,
+        );
+        let syntax = &syntax_set.syntaxes[0];
+
+        let patterns = linked_patterns(syntax, syntax.root_rule_id());
+        assert_eq!(patterns.len(), 1);
+        assert!(matches!(syntax.rule(patterns[0]), Rule::Match(_)));
+    }
+
+    #[test]
+    fn does_not_report_references_to_empty_rules() {
+        let syntax_set = link(
+            r##"{
+              "scopeName": "source.empty",
+              "patterns": [{ "include": "#empty" }, { "match": "a" }],
+              "repository": { "empty": {} }
+            }"##,
+        );
+        let syntax = &syntax_set.syntaxes[0];
+
+        assert_eq!(linked_patterns(syntax, syntax.root_rule_id()).len(), 1);
+        assert!(syntax_set.dropped_references.is_empty());
+    }
+
+    #[test]
+    fn resolves_references_to_other_grammars() {
+        let syntax_set = link_all(&[
+            r#"{
+              "scopeName": "source.a",
+              "patterns": [
+                { "include": "source.b" },
+                { "include": "source.b#letter" },
+                { "include": "source.a" },
+                { "include": "source.missing" },
+                { "include": "source.b#missing" }
+              ]
+            }"#,
+            r#"{
+              "scopeName": "source.b",
+              "patterns": [{ "match": "b" }],
+              "repository": { "letter": { "match": "[a-z]", "name": "b.letter" } }
+            }"#,
+        ]);
+        let a = &syntax_set.syntaxes[0];
+        let b_id = SyntaxId::from_idx(1);
+        let b = syntax_set.syntax(b_id);
+
+        let patterns = a.rule(a.root_rule_id()).patterns().unwrap();
+        let [
+            RuleIdOrReference::ForeignRuleId(b_root_syntax, b_root),
+            RuleIdOrReference::ForeignRuleId(letter_syntax, letter),
+            RuleIdOrReference::RuleId(a_root),
+        ] = patterns[..]
+        else {
+            panic!("unexpected patterns {patterns:?}");
+        };
+        assert_eq!((b_root_syntax, b_root), (b_id, b.root_rule_id()));
+        assert_eq!(letter_syntax, b_id);
+        let letter_name = b.rule(letter).name().unwrap();
+        assert_eq!(syntax_set.scopes.resolve(letter_name), "b.letter");
+        assert_eq!(a_root, a.root_rule_id());
+
+        let dropped: Vec<_> = syntax_set
+            .dropped_references
+            .iter()
+            .map(|dropped| dropped.reference.to_string())
+            .collect();
+        assert_eq!(dropped, vec!["source.missing", "source.b#missing"]);
+    }
+
+    #[test]
+    fn prunes_unreachable_rules() {
+        let mut syntax_set = link_all(&[
+            r##"{
+              "scopeName": "source.a",
+              "patterns": [{ "include": "#used" }, { "include": "source.b#shared" }],
+              "repository": {
+                "used": { "match": "a", "name": "a.used" },
+                "unused": {
+                  "match": "z",
+                  "name": "a.unused",
+                  "captures": { "0": { "name": "a.capture" } }
+                },
+                "dead_end": {
+                  "begin": "<",
+                  "end": ">",
+                  "patterns": [{ "include": "#used" }, { "include": "#missing" }]
+                }
+              }
+            }"##,
+            r#"{
+              "scopeName": "source.b",
+              "patterns": [],
+              "repository": {
+                "lonely": { "match": "c", "name": "b.lonely" },
+                "shared": { "match": "b", "name": "b.shared" }
+              }
+            }"#,
+        ]);
+        syntax_set.prune_unreachable();
+
+        let names = |syntax: &SyntaxDefinition| -> Vec<_> {
+            syntax
+                .rules
+                .iter()
+                .map(|rule| rule.name().map(|name| syntax_set.scopes.resolve(name)))
+                .collect()
+        };
+        let a = &syntax_set.syntaxes[0];
+        let b = &syntax_set.syntaxes[1];
+        assert_eq!(names(a), [None, Some("a.used")]);
+        assert_eq!(names(b), [None, Some("b.shared")]);
+        assert_eq!(Vec::<String>::from(a.regexes.clone()), ["a"]);
+        assert_eq!(a.partial_regexes.len(), 0);
+        assert_eq!(Vec::<String>::from(b.regexes.clone()), ["b"]);
+
+        // the keys stay, but no longer lead anywhere
+        let unused = ScopeName("unused".to_string());
+        let root_stack = a.rule(a.root_rule_id()).repository_stack();
+        assert_eq!(a.lookup_repository_rule(root_stack, &unused), Some(None));
+        let patterns = a.rule(a.root_rule_id()).patterns().unwrap();
+        assert_eq!(
+            patterns,
+            &[
+                RuleIdOrReference::RuleId(RuleId::from_idx(1)),
+                RuleIdOrReference::ForeignRuleId(SyntaxId::from_idx(1), RuleId::from_idx(1)),
+            ]
+        );
+        // the include that was dropped was in a rule that is gone, too
+        assert!(syntax_set.dropped_references.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 use serde_derive::Deserialize;
 use snafu::prelude::*;
 use std::collections::HashMap;
+use std::io::Read;
 
 #[derive(Debug, Snafu)]
 pub(crate) enum Error {
@@ -11,17 +12,41 @@ pub(crate) enum Error {
         )))]
         source: serde_json::Error,
     },
+    #[snafu(display("failed to read plist"))]
+    PlistSyntax { source: plist::Error },
+    #[snafu(display("failed to deserialize plist at \"{}\"", path))]
+    Plist {
+        path: String,
+        #[snafu(source(from(serde_path_to_error::Error<serde_json::Error>, serde_path_to_error::Error::into_inner
+        )))]
+        source: serde_json::Error,
+    },
+    #[snafu(display("failed to deserialize yaml at \"{}\"", path))]
+    Yaml {
+        path: String,
+        #[snafu(source(from(serde_path_to_error::Error<serde_yaml::Error>, serde_path_to_error::Error::into_inner
+        )))]
+        source: serde_yaml::Error,
+    },
 }
 
 // modelled after https://github.com/microsoft/vscode-textmate/blob/f03a6a8790af81372d0e81facae75554ec5e97ef/src/rawGrammar.ts
 // and https://github.com/RedCMD/TmLanguage-Syntax-Highlighter/blob/main/documentation/rules.md
+//
+// This is the only model of a grammar as written: every format (json, plist,
+// yaml) is deserialized into it, and the compiler only ever sees it. Quirks of
+// real world grammars (indexed captures, numeric booleans, ...) are handled here
+// rather than in a separate model per format.
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SyntaxDefinition {
-    // expected to be globally unique
-    // todo: check while linking
+    // expected to be globally unique, which is checked when building a syntax set
     pub(crate) scope_name: ScopeName,
+    // the language's display name, e.g. `Rust`; only kept for listing grammars
+    pub(crate) name: Option<String>,
+    pub(crate) file_types: Option<Vec<String>>,
+    pub(crate) first_line_match: Option<RegExpString>,
     pub(crate) patterns: Vec<Rule>,
 
     pub(crate) repository: Option<Repository>,
@@ -30,11 +55,20 @@ pub(crate) struct SyntaxDefinition {
     pub(crate) injection_selector: Option<String>,
 
     // not in https://github.com/RedCMD/TmLanguage-Syntax-Highlighter/blob/main/documentation/rules.md
-    // but is present in some real world grammars; maybe we should ignore it?
+    // (vscode takes it from the extension manifest instead), but real world grammars
+    // carry it along with `injectionSelector`
     pub(crate) inject_to: Option<Vec<String>>,
-    //
-    // fileTypes, name, and firstLineMatch are present in vscode,
-    // but are apparently ignored, so no point parsing them
+}
+
+impl Rule {
+    // a single rule, e.g. to patch a grammar with
+    pub(crate) fn from_json(json: &str) -> Result<Self, Error> {
+        let des = &mut serde_json::Deserializer::from_str(json);
+
+        serde_path_to_error::deserialize(des).with_context(|e| JsonSnafu {
+            path: e.path().to_string(),
+        })
+    }
 }
 
 impl SyntaxDefinition {
@@ -45,6 +79,111 @@ impl SyntaxDefinition {
             path: e.path().to_string(),
         })
     }
+
+    // JSON with comments and trailing commas, as VS Code reads its own files;
+    // they are blanked out so that errors still point at the right line and column
+    pub(crate) fn from_jsonc(jsonc: &str) -> Result<Self, Error> {
+        Self::from_json(&strip_jsonc(jsonc))
+    }
+
+    // streams the grammar instead of holding its text in memory next to the parsed
+    // grammar; read errors surface as json errors at the path that was being read
+    pub(crate) fn from_reader(reader: impl Read) -> Result<Self, Error> {
+        let des = &mut serde_json::Deserializer::from_reader(reader);
+
+        serde_path_to_error::deserialize(des).with_context(|e| JsonSnafu {
+            path: e.path().to_string(),
+        })
+    }
+
+    // classic .tmLanguage files are XML plists with the same keys as JSON grammars;
+    // plist's own serde deserializer is behind an unstable feature, so the plist is
+    // first read into a json tree and then deserialized exactly like a json grammar
+    pub(crate) fn from_plist(xml: &str) -> Result<Self, Error> {
+        let value: serde_json::Value =
+            plist::from_reader_xml(xml.as_bytes()).context(PlistSyntaxSnafu)?;
+
+        serde_path_to_error::deserialize(value).with_context(|e| PlistSnafu {
+            path: e.path().to_string(),
+        })
+    }
+
+    pub(crate) fn from_yaml(yaml: &str) -> Result<Self, Error> {
+        let des = serde_yaml::Deserializer::from_str(yaml);
+
+        serde_path_to_error::deserialize(des).with_context(|e| YamlSnafu {
+            path: e.path().to_string(),
+        })
+    }
+}
+
+// Replaces `//` and `/* */` comments and commas before a closing bracket with
+// spaces, leaving strings alone; an unterminated comment runs to the end.
+fn strip_jsonc(jsonc: &str) -> String {
+    let mut json = String::with_capacity(jsonc.len());
+    let mut chars = jsonc.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                json.push(c);
+            }
+            ('/', Some('/')) => {
+                json.push(' ');
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    json.push(' ');
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                json.push_str("  ");
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    json.push(if c == '\n' { '\n' } else { ' ' });
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            _ => json.push(c),
+        }
+    }
+
+    // with the comments gone, a trailing comma is only followed by whitespace
+    let mut bytes = json.into_bytes();
+    let mut in_string = false;
+    let mut escaped = false;
+    for idx in 0..bytes.len() {
+        match bytes[idx] {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            b',' if !in_string => {
+                let next = bytes[idx + 1..].iter().find(|b| !b.is_ascii_whitespace());
+                if matches!(next, Some(b'}' | b']')) {
+                    bytes[idx] = b' ';
+                }
+            }
+            _ => {}
+        }
+    }
+    // only ASCII was replaced with ASCII
+    String::from_utf8(bytes).unwrap()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -132,8 +271,91 @@ pub(crate) struct Rule {
     pub(crate) apply_end_pattern_last: Option<bool>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub(crate) struct Captures(pub(crate) HashMap<String, Rule>);
+#[derive(Debug, Clone)]
+pub(crate) enum Captures {
+    // capture index (as written in the grammar) to rule
+    Named(HashMap<String, Rule>),
+    // the position in the sequence is the capture index
+    Indexed(Vec<Rule>),
+}
+
+// captures are normally a map from capture index to a rule, but some grammars
+// (e.g. jinja.json and hand-written yaml ones) list them as a sequence; yaml also
+// happily produces integer keys, which a plain HashMap<String, _> would reject.
+// This is an untagged enum in all but name: the derived one loses error paths.
+impl<'de> serde::Deserialize<'de> for Captures {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct CapturesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for CapturesVisitor {
+            type Value = Captures;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a map or a sequence of captures")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut captures = HashMap::new();
+                while let Some((CaptureKey(key), rule)) = map.next_entry()? {
+                    captures.insert(key, rule);
+                }
+                Ok(Captures::Named(captures))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let mut captures = Vec::new();
+                while let Some(rule) = seq.next_element()? {
+                    captures.push(rule);
+                }
+                Ok(Captures::Indexed(captures))
+            }
+        }
+
+        deserializer.deserialize_any(CapturesVisitor)
+    }
+}
+
+struct CaptureKey(String);
+
+impl<'de> serde::Deserialize<'de> for CaptureKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct CaptureKeyVisitor;
+
+        impl serde::de::Visitor<'_> for CaptureKeyVisitor {
+            type Value = CaptureKey;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a capture index")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(CaptureKey(v.to_string()))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(CaptureKey(v.to_string()))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(CaptureKey(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(CaptureKeyVisitor)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -150,10 +372,166 @@ mod tests {
         "stata.json",
         "racket.json",
         "xml.json",
-        "jinja.json",
         "smalltalk.json",
     ];
 
+    const ABC_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>scopeName</key>
+    <string>source.abc</string>
+    <key>patterns</key>
+    <array>
+        <dict>
+            <key>include</key>
+            <string>#paren-expression</string>
+        </dict>
+    </array>
+    <key>repository</key>
+    <dict>
+        <key>paren-expression</key>
+        <dict>
+            <key>begin</key>
+            <string>\(</string>
+            <key>end</key>
+            <string>\)</string>
+            <key>beginCaptures</key>
+            <dict>
+                <key>0</key>
+                <dict>
+                    <key>name</key>
+                    <string>punctuation.paren.open</string>
+                </dict>
+            </dict>
+            <key>applyEndPatternLast</key>
+            <integer>1</integer>
+        </dict>
+        <key>quote</key>
+        <dict>
+            <key>begin</key>
+            <string>^&gt;</string>
+            <key>while</key>
+            <string>^&gt;</string>
+        </dict>
+    </dict>
+</dict>
+</plist>"#;
+
+    #[test]
+    fn can_load_plist_grammar() {
+        let syntax = SyntaxDefinition::from_plist(ABC_PLIST).unwrap();
+        assert_eq!(syntax.scope_name.0, "source.abc");
+        assert_eq!(
+            syntax.patterns[0].include.as_ref().unwrap().0,
+            "#paren-expression"
+        );
+
+        let repository = syntax.repository.unwrap().0;
+        let paren = &repository["paren-expression"];
+        assert_eq!(paren.begin.as_ref().unwrap().0, "\\(");
+        assert_eq!(paren.end.as_ref().unwrap().0, "\\)");
+        assert_eq!(paren.apply_end_pattern_last, Some(true));
+        assert_eq!(
+            named_capture(paren.begin_captures.as_ref().unwrap(), "0"),
+            "punctuation.paren.open"
+        );
+
+        let quote = &repository["quote"];
+        assert_eq!(quote.while_.as_ref().unwrap().0, "^>");
+    }
+
+    fn named_capture<'a>(captures: &'a Captures, index: &str) -> &'a str {
+        let Captures::Named(captures) = captures else {
+            panic!("expected named captures");
+        };
+        &captures[index].name.as_ref().unwrap().0
+    }
+
+    const ABC_YAML: &str = r##"
+scopeName: source.abc
+patterns:
+  - include: "#paren-expression"
+  - include: "#letters"
+repository:
+  paren-expression:
+    begin: \(
+    end: \)
+    beginCaptures:
+      0:
+        name: punctuation.paren.open
+    applyEndPatternLast: 1
+  letters:
+    match: (a)|(b)
+    captures:
+      - name: keyword.letters
+      - name: keyword.letter.a
+      - name: keyword.letter.b
+"##;
+
+    #[test]
+    fn can_load_yaml_grammar() {
+        let syntax = SyntaxDefinition::from_yaml(ABC_YAML).unwrap();
+        assert_eq!(syntax.scope_name.0, "source.abc");
+
+        let repository = syntax.repository.unwrap().0;
+        let paren = &repository["paren-expression"];
+        assert_eq!(paren.begin.as_ref().unwrap().0, "\\(");
+        assert_eq!(paren.apply_end_pattern_last, Some(true));
+        assert_eq!(
+            named_capture(paren.begin_captures.as_ref().unwrap(), "0"),
+            "punctuation.paren.open"
+        );
+
+        let Some(Captures::Indexed(letters)) = &repository["letters"].captures else {
+            panic!("expected indexed captures");
+        };
+        assert_eq!(letters.len(), 3);
+        assert_eq!(letters[2].name.as_ref().unwrap().0, "keyword.letter.b");
+    }
+
+    #[test]
+    fn yaml_errors_carry_path() {
+        let err = SyntaxDefinition::from_yaml("scopeName: source.abc\npatterns: 3\n").unwrap_err();
+        assert!(matches!(err, Error::Yaml { ref path, .. } if path == "patterns"));
+    }
+
+    #[test]
+    fn can_load_jsonc_grammar() {
+        let syntax = SyntaxDefinition::from_jsonc(
+            r#"{
+              // a comment
+              "scopeName": "source.commented", /* another
+              one */
+              "patterns": [
+                { "match": "a//b/*c*/\\\"", "name": "trailing", },
+              ],
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(syntax.scope_name.0, "source.commented");
+        assert_eq!(
+            syntax.patterns[0].match_.as_ref().unwrap().0,
+            r#"a//b/*c*/\""#
+        );
+        assert!(SyntaxDefinition::from_json(r#"{ "scopeName": "a", }"#).is_err());
+
+        let err = SyntaxDefinition::from_jsonc("{\n  /* unclosed\n }").unwrap_err();
+        assert!(matches!(err, Error::Json { source, .. } if source.line() == 3));
+    }
+
+    #[test]
+    fn streams_json_grammars() {
+        let json = r#"{ "scopeName": "source.abc", "patterns": [{ "match": "a" }] }"#;
+        let syntax = SyntaxDefinition::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(syntax.scope_name.0, "source.abc");
+        assert_eq!(syntax.patterns.len(), 1);
+
+        let broken = r#"{ "scopeName": "source.abc", "patterns": [{ "match": 3 }] }"#;
+        let err = SyntaxDefinition::from_reader(broken.as_bytes()).unwrap_err();
+        assert!(matches!(err, Error::Json { ref path, .. } if path == "patterns[0].match"));
+    }
+
     #[test_case(RAW_GRAMMARS_PATH ; "raw")]
     #[test_case(CLEANED_GRAMMARS_PATH ; "cleaned")]
     fn can_load_grammars(grammars_path: &'static str) -> Report<Whatever> {
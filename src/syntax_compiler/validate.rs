@@ -0,0 +1,244 @@
+use crate::syntax_compiler::compile::{RepositoryId, RuleId, SyntaxId, SyntaxSet};
+use std::fmt;
+
+/// A problem in a grammar that doesn't stop it from loading, reported by
+/// [`SyntaxSet::validate`](crate::SyntaxSet::validate).
+///
+/// `scope` is the scope name of the grammar and `rule` says where in it the
+/// problem is, e.g. `repository.string > patterns[2]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationWarning {
+    /// An include that doesn't point to any rule, e.g. of a grammar that isn't
+    /// loaded. Its pattern never matches.
+    UnresolvedInclude {
+        scope: String,
+        rule: String,
+        include: String,
+    },
+    /// A repository rule that no include refers to.
+    UnusedRepositoryRule { scope: String, rule: String },
+    /// A rule with neither `match`, `begin` nor `patterns`, which never matches.
+    EmptyRule { scope: String, rule: String },
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationWarning::UnresolvedInclude {
+                scope,
+                rule,
+                include,
+            } => write!(f, "{scope}: {rule} includes {include}, which doesn't exist"),
+            ValidationWarning::UnusedRepositoryRule { scope, rule } => {
+                write!(f, "{scope}: {rule} is never included")
+            }
+            ValidationWarning::EmptyRule { scope, rule } => {
+                write!(f, "{scope}: {rule} matches nothing")
+            }
+        }
+    }
+}
+
+impl SyntaxSet {
+    // Reports what the linker and compiler silently drop, so that grammar authors
+    // learn about it. Needs a linked set.
+    pub(crate) fn validate(&self) -> Vec<ValidationWarning> {
+        let mut warnings = Vec::new();
+
+        for (syntax_idx, syntax) in self.syntaxes.iter().enumerate() {
+            let syntax_id = SyntaxId::from_idx(syntax_idx);
+            let scope = &syntax.scope_name.0;
+
+            for dropped in &self.dropped_references {
+                if dropped.syntax_id == syntax_id {
+                    // identical rules are merged, so the include can be in several places
+                    for rule in self.describe_rule(syntax_id, dropped.rule_id) {
+                        warnings.push(ValidationWarning::UnresolvedInclude {
+                            scope: scope.clone(),
+                            rule,
+                            include: dropped.reference.to_string(),
+                        });
+                    }
+                }
+            }
+
+            for (repository_idx, repository) in syntax.repositories.iter().enumerate() {
+                let repository_id = RepositoryId::try_from_idx(repository_idx)
+                    .expect("repositories have ids once compiled");
+                let mut unused: Vec<_> = repository
+                    .rules
+                    .iter()
+                    .filter(|(key, rule_id)| {
+                        let included = self.included_keys.contains(&(
+                            syntax_id,
+                            repository_id,
+                            (*key).clone(),
+                        ));
+                        rule_id.is_some() && !included
+                    })
+                    .map(|(key, _)| &key.0)
+                    .collect();
+                unused.sort();
+                warnings.extend(unused.into_iter().map(|key| {
+                    ValidationWarning::UnusedRepositoryRule {
+                        scope: scope.clone(),
+                        rule: format!("repository.{key}"),
+                    }
+                }));
+            }
+
+            // listed by where they are rather than in the order they were compiled
+            let mut empty: Vec<_> = syntax.empty_rules.iter().collect();
+            empty.sort();
+            warnings.extend(empty.into_iter().map(|rule| ValidationWarning::EmptyRule {
+                scope: scope.clone(),
+                rule: rule.clone(),
+            }));
+        }
+
+        warnings
+    }
+
+    // Rules are named by their repository keys (several, if identical rules
+    // were merged), else by where they were compiled from if the rule-origins
+    // feature recorded it, else by their scope
+    fn describe_rule(&self, syntax_id: SyntaxId, rule_id: RuleId) -> Vec<String> {
+        let syntax = self.syntax(syntax_id);
+        if rule_id == syntax.root_rule_id() {
+            return vec!["patterns".to_string()];
+        }
+        let mut keys: Vec<_> = syntax
+            .repositories
+            .iter()
+            .flat_map(|repository| &repository.rules)
+            .filter(|(_, id)| **id == Some(rule_id))
+            .map(|(key, _)| format!("repository.{}", key.0))
+            .collect();
+        if !keys.is_empty() {
+            keys.sort();
+            keys.dedup();
+            return keys;
+        }
+        if let Some(origin) = syntax.rule_origin(rule_id) {
+            // without the grammar, which every warning names anyway
+            let prefix = format!("{} > ", syntax.scope_name.0);
+            return vec![origin.strip_prefix(&prefix).unwrap_or(origin).to_string()];
+        }
+        vec![match syntax.rule(rule_id).name() {
+            Some(name) => format!("rule #{} ({})", rule_id.to_idx(), self.scopes.resolve(name)),
+            None => format!("rule #{}", rule_id.to_idx()),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_compiler::compile::{ScopeInterner, SyntaxDefinition};
+    use crate::syntax_compiler::parse;
+
+    fn validate(jsons: &[&str]) -> Vec<String> {
+        let mut scopes = ScopeInterner::default();
+        let syntaxes = jsons
+            .iter()
+            .map(|json| {
+                let parsed = parse::SyntaxDefinition::from_json(json).unwrap();
+                SyntaxDefinition::compile(parsed, &mut scopes).unwrap()
+            })
+            .collect();
+        let mut syntax_set = SyntaxSet::new(syntaxes, scopes).unwrap();
+        syntax_set.link();
+        syntax_set
+            .validate()
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reports_what_would_be_dropped() {
+        let warnings = validate(&[
+            r##"{
+              "scopeName": "source.a",
+              "patterns": [{ "include": "#used" }, { "include": "source.css" }, {}],
+              "repository": {
+                "used": {
+                  "begin": "\\(",
+                  "end": "\\)",
+                  "name": "group",
+                  "patterns": [{ "include": "#missing" }, { "include": "source.b#shared" }]
+                },
+                "unused": { "match": "a" },
+                "empty": {}
+              }
+            }"##,
+            r##"{
+              "scopeName": "source.b",
+              "patterns": [],
+              "repository": { "shared": { "match": "b" } }
+            }"##,
+        ]);
+
+        assert_eq!(
+            warnings,
+            [
+                "source.a: patterns includes source.css, which doesn't exist",
+                "source.a: repository.used includes #missing, which doesn't exist",
+                "source.a: repository.unused is never included",
+                "source.a: patterns[2] matches nothing",
+                "source.a: repository.empty matches nothing",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_keys_whose_rules_were_merged() {
+        // identical rules compile into one, which only one of the keys includes
+        let warnings = validate(&[r##"{
+          "scopeName": "source.merged",
+          "patterns": [{ "include": "#used" }],
+          "repository": {
+            "used": { "match": "a", "name": "x" },
+            "unused": { "match": "a", "name": "x" },
+            "broken": { "begin": "\\(", "end": "\\)", "patterns": [{ "include": "#missing" }] },
+            "copy": { "begin": "\\(", "end": "\\)", "patterns": [{ "include": "#missing" }] }
+          }
+        }"##]);
+
+        assert_eq!(
+            warnings,
+            [
+                "source.merged: repository.broken includes #missing, which doesn't exist",
+                "source.merged: repository.copy includes #missing, which doesn't exist",
+                "source.merged: repository.broken is never included",
+                "source.merged: repository.copy is never included",
+                "source.merged: repository.unused is never included",
+            ]
+        );
+    }
+
+    #[cfg(feature = "rule-origins")]
+    #[test]
+    fn names_rules_by_their_origin() {
+        let warnings = validate(&[r##"{
+          "scopeName": "source.origins",
+          "patterns": [{ "begin": "\\(", "end": "\\)", "patterns": [{ "include": "#missing" }] }]
+        }"##]);
+
+        assert_eq!(
+            warnings,
+            ["source.origins: patterns[0] includes #missing, which doesn't exist"]
+        );
+    }
+
+    #[test]
+    fn accepts_a_clean_grammar() {
+        let warnings = validate(&[r##"{
+          "scopeName": "source.clean",
+          "patterns": [{ "include": "#letter" }],
+          "repository": { "letter": { "match": "a", "name": "letter" } }
+        }"##]);
+
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+}
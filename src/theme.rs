@@ -0,0 +1,761 @@
+use crate::selector::Selector;
+use serde_derive::Deserialize;
+use snafu::prelude::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// An error that occurred while loading a theme.
+#[derive(Debug, Snafu)]
+pub struct ThemeError(InnerError);
+
+#[derive(Debug, Snafu)]
+enum InnerError {
+    #[snafu(display("failed to deserialize theme json at \"{}\"", path))]
+    Json {
+        path: String,
+        #[snafu(source(from(serde_path_to_error::Error<serde_json::Error>, serde_path_to_error::Error::into_inner
+        )))]
+        source: serde_json::Error,
+    },
+    #[snafu(display("failed to read included theme \"{}\"", include))]
+    Read {
+        include: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("failed to load included theme \"{}\"", include))]
+    Include {
+        include: String,
+        source: Box<ThemeError>,
+    },
+    #[snafu(display("theme includes itself through \"{}\"", include))]
+    IncludeCycle { include: String },
+}
+
+/// A color with 8 bits per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Rgba {
+    /// Parses a CSS-like hex color: `#rrggbb`, `#rrggbbaa` or the shorthands
+    /// `#rgb` and `#rgba`. Colors without alpha are opaque.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.strip_prefix('#')?;
+        if !hex.is_ascii() {
+            return None;
+        }
+
+        let channel: &dyn Fn(usize) -> Option<u8> = match hex.len() {
+            6 | 8 => &|idx| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok(),
+            // `#abc` is `#aabbcc`
+            3 | 4 => &|idx| Some(u8::from_str_radix(&hex[idx..idx + 1], 16).ok()? * 0x11),
+            _ => return None,
+        };
+        Some(Self {
+            r: channel(0)?,
+            g: channel(1)?,
+            b: channel(2)?,
+            a: if matches!(hex.len(), 4 | 8) {
+                channel(3)?
+            } else {
+                0xff
+            },
+        })
+    }
+
+    /// The opaque color this one looks like when drawn over `background`.
+    pub fn over(self, background: Rgba) -> Rgba {
+        let blend = |fg: u8, bg: u8| {
+            let (fg, bg, a) = (u32::from(fg), u32::from(bg), u32::from(self.a));
+            ((fg * a + bg * (0xff - a) + 0x7f) / 0xff) as u8
+        };
+        Rgba {
+            r: blend(self.r, background.r),
+            g: blend(self.g, background.g),
+            b: blend(self.b, background.b),
+            a: 0xff,
+        }
+    }
+
+    /// The closest color of the 256-color xterm palette, out of its 6×6×6 color
+    /// cube and 24 grays; the 16 system colors are left out, since terminals
+    /// let users change them.
+    pub fn to_ansi_256(self) -> u8 {
+        // the cube's levels are 0, 95, 135, 175, 215 and 255
+        let level = |v: u8| {
+            if v < 48 {
+                0
+            } else if v < 115 {
+                1
+            } else {
+                (v - 35) / 40
+            }
+        };
+        let level_value = |level: u8| if level == 0 { 0 } else { 55 + level * 40 };
+        let (r, g, b) = (level(self.r), level(self.g), level(self.b));
+        let cube = (
+            16 + 36 * r + 6 * g + b,
+            [level_value(r), level_value(g), level_value(b)],
+        );
+
+        // the grays are 8, 18, ..., 238
+        let average = (u16::from(self.r) + u16::from(self.g) + u16::from(self.b)) / 3;
+        let gray_idx = (average.saturating_sub(3) / 10).min(23) as u8;
+        let gray_value = 8 + gray_idx * 10;
+        let gray = (232 + gray_idx, [gray_value; 3]);
+
+        let distance = |[r, g, b]: [u8; 3]| {
+            let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+            d(r, self.r) + d(g, self.g) + d(b, self.b)
+        };
+        if distance(gray.1) < distance(cube.1) {
+            gray.0
+        } else {
+            cube.0
+        }
+    }
+}
+
+/// Font flags of a [`Style`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FontStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl FontStyle {
+    // space separated flags, e.g. `bold italic`; an empty string resets them all
+    fn parse(font_style: &str) -> Self {
+        let mut parsed = Self::default();
+        for flag in font_style.split_whitespace() {
+            match flag {
+                "bold" => parsed.bold = true,
+                "italic" => parsed.italic = true,
+                "underline" => parsed.underline = true,
+                // e.g. `strikethrough`, which terminals mostly can't show anyway
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+/// How a token should look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Style {
+    pub foreground: Option<Rgba>,
+    pub background: Option<Rgba>,
+    pub font_style: FontStyle,
+}
+
+impl Style {
+    /// Makes translucent colors opaque by drawing them over `background`,
+    /// usually the theme's default one; terminals have no notion of alpha.
+    ///
+    /// The foreground is drawn over the style's own background if it has one.
+    /// Without a `background` colors are left as they are.
+    pub fn over(self, background: Option<Rgba>) -> Style {
+        let Some(background) = background else {
+            return self;
+        };
+        let own_background = self.background.map(|color| color.over(background));
+        let foreground_background = own_background.unwrap_or(background);
+        Style {
+            foreground: self
+                .foreground
+                .map(|color| color.over(foreground_background)),
+            background: own_background,
+            ..self
+        }
+    }
+
+    /// The escape sequence that switches a terminal with 24-bit color support
+    /// to this style. Alpha is ignored, see [`Style::over`].
+    ///
+    /// The sequence starts with a reset, so nothing of the previous style
+    /// carries over.
+    pub fn to_ansi_truecolor(self) -> String {
+        self.escape_sequence(|output, layer, Rgba { r, g, b, .. }| {
+            write!(output, ";{layer};2;{r};{g};{b}").unwrap()
+        })
+    }
+
+    /// Like [`Style::to_ansi_truecolor`], for terminals with 256 colors: every
+    /// color is replaced with the closest one of the palette.
+    pub fn to_ansi_256(self) -> String {
+        self.escape_sequence(|output, layer, color| {
+            write!(output, ";{layer};5;{}", color.to_ansi_256()).unwrap()
+        })
+    }
+
+    // `layer` is 38 for the foreground and 48 for the background
+    fn escape_sequence(self, mut push_color: impl FnMut(&mut String, u8, Rgba)) -> String {
+        let mut output = String::from("\x1b[0");
+        if let Some(color) = self.foreground {
+            push_color(&mut output, 38, color);
+        }
+        if let Some(color) = self.background {
+            push_color(&mut output, 48, color);
+        }
+        if self.font_style.bold {
+            output.push_str(";1");
+        }
+        if self.font_style.italic {
+            output.push_str(";3");
+        }
+        if self.font_style.underline {
+            output.push_str(";4");
+        }
+        output.push('m');
+        output
+    }
+}
+
+/// A VS Code color theme, mapping scopes to styles.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    default: Style,
+    rules: Vec<ThemeRule>,
+}
+
+#[derive(Debug, Clone)]
+struct ThemeRule {
+    selector: Selector,
+    // unset properties don't override the default style
+    foreground: Option<Rgba>,
+    background: Option<Rgba>,
+    font_style: Option<FontStyle>,
+}
+
+impl Theme {
+    /// Loads a theme from VS Code's JSON format.
+    ///
+    /// Only `tokenColors` and the editor's default colors are used. As in VS Code,
+    /// colors that can't be parsed are ignored. The theme has to be self-contained:
+    /// `include` is ignored, see [`Theme::from_json_with_loader`] for themes that
+    /// extend another one.
+    pub fn from_json(json: &str) -> Result<Self, ThemeError> {
+        Ok(Self::from_raw(RawTheme::from_json(json)?))
+    }
+
+    /// Like [`Theme::from_json`], but also loads the theme named by `include`,
+    /// e.g. `./dark_plus.json`, and the ones that one includes in turn.
+    ///
+    /// `load` is given the `include` as written, and the includes that led to
+    /// the theme it is in, outermost first (none for `json` itself). It returns
+    /// the included theme's JSON; resolving it, e.g. relative to the theme that
+    /// includes it, is up to it. The included theme's colors and rules come
+    /// first, so the including theme's override them. Themes that include
+    /// themselves fail to load.
+    pub fn from_json_with_loader(
+        json: &str,
+        mut load: impl FnMut(&str, &[String]) -> std::io::Result<String>,
+    ) -> Result<Self, ThemeError> {
+        let raw = RawTheme::from_json(json)?.with_includes(&mut load, &mut Vec::new())?;
+        Ok(Self::from_raw(raw))
+    }
+
+    fn from_raw(raw: RawTheme) -> Self {
+        let color = |key: &str| {
+            raw.colors
+                .get(key)
+                .and_then(|color| color.as_str())
+                .and_then(Rgba::from_hex)
+        };
+        let mut default = Style {
+            foreground: color("editor.foreground"),
+            background: color("editor.background"),
+            font_style: FontStyle::default(),
+        };
+
+        let mut rules = Vec::new();
+        for raw_rule in raw.token_colors {
+            let settings = raw_rule.settings;
+            let foreground = settings.foreground.as_deref().and_then(Rgba::from_hex);
+            let background = settings.background.as_deref().and_then(Rgba::from_hex);
+            let font_style = settings.font_style.as_deref().map(FontStyle::parse);
+
+            let selector = match raw_rule.scope {
+                Some(RawScope::One(scope)) => scope,
+                Some(RawScope::Many(scopes)) => scopes.join(", "),
+                None => String::new(),
+            };
+
+            // rules without a scope set the defaults, like tmTheme's global settings
+            if selector.trim().is_empty() {
+                default.foreground = foreground.or(default.foreground);
+                default.background = background.or(default.background);
+                default.font_style = font_style.unwrap_or(default.font_style);
+                continue;
+            }
+
+            rules.push(ThemeRule {
+                selector: Selector::parse(&selector),
+                foreground,
+                background,
+                font_style,
+            });
+        }
+
+        Self { default, rules }
+    }
+
+    /// The style of text that no theme rule applies to.
+    pub fn default_style(&self) -> Style {
+        self.default
+    }
+
+    /// Resolves the style for a scope stack, outermost scope first.
+    ///
+    /// Every property comes from the most specific matching rule that sets it,
+    /// so a rule can e.g. only make text italic and keep the foreground of a
+    /// broader one; properties no rule sets are the default ones.
+    ///
+    /// Rules are scored like injection selectors: first the one matching the
+    /// deepest scope, then the one naming more of the scopes (`string.quoted`
+    /// over `string`, `source string` over `string`). Ties go to the rule
+    /// declared last, as in VS Code.
+    pub fn style_for<S: AsRef<str>>(&self, scope_stack: &[S]) -> Style {
+        let mut matched: Vec<_> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, rule)| Some(((rule.selector.matches(scope_stack)?, idx), rule)))
+            .collect();
+        // most specific first
+        matched.sort_unstable_by_key(|(key, _)| std::cmp::Reverse(*key));
+
+        let rules = || matched.iter().map(|(_, rule)| rule);
+        Style {
+            foreground: rules()
+                .find_map(|rule| rule.foreground)
+                .or(self.default.foreground),
+            background: rules()
+                .find_map(|rule| rule.background)
+                .or(self.default.background),
+            font_style: rules()
+                .find_map(|rule| rule.font_style)
+                .unwrap_or(self.default.font_style),
+        }
+    }
+
+    /// The scopes no theme rule applies to, e.g. those of
+    /// [`Grammar::scopes`](crate::Grammar::scopes), in the order they're given.
+    ///
+    /// Every scope is matched on its own, so rules whose selectors need a
+    /// parent scope (e.g. `string source`) don't count.
+    pub fn uncovered_scopes<'s>(&self, scopes: impl IntoIterator<Item = &'s str>) -> Vec<&'s str> {
+        scopes
+            .into_iter()
+            .filter(|scope| {
+                let stack = [*scope];
+                !self
+                    .rules
+                    .iter()
+                    .any(|rule| rule.selector.matches(&stack).is_some())
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawTheme {
+    include: Option<String>,
+    #[serde(default)]
+    colors: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    token_colors: Vec<RawThemeRule>,
+}
+
+impl RawTheme {
+    fn from_json(json: &str) -> Result<Self, ThemeError> {
+        let des = &mut serde_json::Deserializer::from_str(json);
+        let raw = serde_path_to_error::deserialize(des).with_context(|e| JsonSnafu {
+            path: e.path().to_string(),
+        })?;
+        Ok(raw)
+    }
+
+    // merges the chain of included themes into this one; `including` are the
+    // includes that led here, to detect cycles
+    fn with_includes(
+        mut self,
+        load: &mut impl FnMut(&str, &[String]) -> std::io::Result<String>,
+        including: &mut Vec<String>,
+    ) -> Result<Self, ThemeError> {
+        let Some(include) = self.include.take() else {
+            return Ok(self);
+        };
+        ensure!(!including.contains(&include), IncludeCycleSnafu { include });
+
+        let json = load(&include, including).context(ReadSnafu { include: &include })?;
+        including.push(include);
+        let base = RawTheme::from_json(&json).and_then(|base| base.with_includes(load, including));
+        let include = including.pop().unwrap();
+        let mut base = base.map_err(Box::new).context(IncludeSnafu { include })?;
+
+        base.colors.extend(self.colors);
+        base.token_colors.extend(self.token_colors);
+        Ok(base)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawThemeRule {
+    scope: Option<RawScope>,
+    #[serde(default)]
+    settings: RawSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawScope {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawSettings {
+    foreground: Option<String>,
+    background: Option<String>,
+    font_style: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THEME_JSON: &str = r##"{
+      "name": "test",
+      "colors": { "editor.foreground": "#d4d4d4", "editor.background": "#1e1e1e" },
+      "tokenColors": [
+        { "settings": { "fontStyle": "" } },
+        { "scope": "string", "settings": { "foreground": "#ce9178" } },
+        { "scope": ["keyword", "storage"], "settings": { "foreground": "#569cd6", "fontStyle": "bold" } },
+        { "scope": "string.quoted.double", "settings": { "foreground": "#ff0000", "background": "#00000080" } },
+        { "scope": "comment", "settings": { "foreground": "not a color" } }
+      ]
+    }"##;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Option<Rgba> {
+        Some(Rgba { r, g, b, a: 0xff })
+    }
+
+    #[test]
+    fn resolves_styles_for_scopes() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+
+        let default = theme.default_style();
+        assert_eq!(default.foreground, rgb(0xd4, 0xd4, 0xd4));
+        assert_eq!(default.background, rgb(0x1e, 0x1e, 0x1e));
+        assert_eq!(theme.style_for(&["source.js"]), default);
+
+        let string = theme.style_for(&["source.js", "string.quoted.single"]);
+        assert_eq!(string.foreground, rgb(0xce, 0x91, 0x78));
+        assert_eq!(string.background, default.background);
+
+        let storage = theme.style_for(&["source.js", "storage.type"]);
+        assert_eq!(storage.foreground, rgb(0x56, 0x9c, 0xd6));
+        assert!(storage.font_style.bold);
+
+        // an invalid color falls back to the default one
+        let comment = theme.style_for(&["source.js", "comment.line"]);
+        assert_eq!(comment.foreground, default.foreground);
+    }
+
+    #[test]
+    fn more_specific_selectors_win() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+
+        let style = theme.style_for(&["source.js", "string.quoted.double"]);
+        assert_eq!(style.foreground, rgb(0xff, 0, 0));
+        assert_eq!(
+            style.background,
+            Some(Rgba {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0x80
+            })
+        );
+    }
+
+    #[test]
+    fn descendant_selectors_and_later_rules_win() {
+        let theme = Theme::from_json(
+            r##"{
+              "tokenColors": [
+                { "scope": "source string", "settings": { "foreground": "#000001" } },
+                { "scope": "string", "settings": { "foreground": "#000002" } },
+                { "scope": "comment", "settings": { "foreground": "#000003" } },
+                { "scope": "comment", "settings": { "foreground": "#000004" } }
+              ]
+            }"##,
+        )
+        .unwrap();
+
+        let foreground = |stack: &[&str]| theme.style_for(stack).foreground;
+        assert_eq!(foreground(&["source.js", "string.quoted"]), rgb(0, 0, 1));
+        assert_eq!(foreground(&["text.html", "string.quoted"]), rgb(0, 0, 2));
+        assert_eq!(foreground(&["source.js", "comment.line"]), rgb(0, 0, 4));
+        // the innermost scope counts more than the selector's length
+        assert_eq!(
+            foreground(&["source.js", "string", "comment"]),
+            rgb(0, 0, 4)
+        );
+    }
+
+    #[test]
+    fn merges_properties_of_matching_rules() {
+        let theme = Theme::from_json(
+            r##"{
+              "colors": { "editor.foreground": "#ffffff" },
+              "tokenColors": [
+                { "scope": "comment", "settings": { "foreground": "#000001", "background": "#000002" } },
+                { "scope": "comment.block", "settings": { "fontStyle": "italic underline" } },
+                { "scope": "comment.block.documentation", "settings": { "background": "#000003" } }
+              ]
+            }"##,
+        )
+        .unwrap();
+
+        let style = theme.style_for(&["source.rs", "comment.block.documentation"]);
+        assert_eq!(style.foreground, rgb(0, 0, 1));
+        assert_eq!(style.background, rgb(0, 0, 3));
+        assert_eq!(
+            style.font_style,
+            FontStyle {
+                bold: false,
+                italic: true,
+                underline: true
+            }
+        );
+
+        let style = theme.style_for(&["source.rs", "comment.line"]);
+        assert_eq!(style.background, rgb(0, 0, 2));
+        assert_eq!(style.font_style, FontStyle::default());
+    }
+
+    #[test]
+    fn reports_uncovered_scopes() {
+        let theme = Theme::from_json(THEME_JSON).unwrap();
+
+        let scopes = [
+            "comment.line",
+            "string.quoted",
+            "entity.name",
+            "keyword",
+            "variable",
+        ];
+        assert_eq!(theme.uncovered_scopes(scopes), ["entity.name", "variable"]);
+    }
+
+    #[test]
+    fn merges_included_themes() {
+        let themes = HashMap::from([
+            (
+                "./base.json",
+                r##"{
+                  "include": "./base-base.json",
+                  "colors": { "editor.background": "#000002" },
+                  "tokenColors": [{ "scope": "string", "settings": { "foreground": "#000002" } }]
+                }"##,
+            ),
+            (
+                "./base-base.json",
+                r##"{
+                  "colors": { "editor.foreground": "#000001", "editor.background": "#000001" },
+                  "tokenColors": [{ "scope": "keyword", "settings": { "foreground": "#000001" } }]
+                }"##,
+            ),
+        ]);
+        let load = |include: &str, _: &[String]| {
+            let json = themes.get(include).ok_or(std::io::ErrorKind::NotFound)?;
+            Ok(json.to_string())
+        };
+
+        let theme = Theme::from_json_with_loader(
+            r##"{
+              "include": "./base.json",
+              "tokenColors": [{ "scope": "string", "settings": { "foreground": "#000003" } }]
+            }"##,
+            load,
+        )
+        .unwrap();
+        assert_eq!(theme.default_style().foreground, rgb(0, 0, 1));
+        assert_eq!(theme.default_style().background, rgb(0, 0, 2));
+        assert_eq!(theme.style_for(&["keyword"]).foreground, rgb(0, 0, 1));
+        assert_eq!(theme.style_for(&["string"]).foreground, rgb(0, 0, 3));
+
+        let err =
+            Theme::from_json_with_loader(r#"{ "include": "./missing.json" }"#, load).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to read included theme \"./missing.json\""
+        );
+    }
+
+    #[test]
+    fn tells_loaders_which_themes_include() {
+        let themes = HashMap::from([
+            (
+                "themes/base.json",
+                r##"{
+                  "include": "./common/colors.json",
+                  "tokenColors": [{ "scope": "string", "settings": { "foreground": "#000002" } }]
+                }"##,
+            ),
+            (
+                "themes/common/colors.json",
+                r##"{
+                  "include": "../shared.json",
+                  "tokenColors": [{ "scope": "string", "settings": { "foreground": "#000001" } }]
+                }"##,
+            ),
+            (
+                "themes/shared.json",
+                r##"{ "tokenColors": [{ "scope": "keyword", "settings": { "foreground": "#000001" } }] }"##,
+            ),
+        ]);
+        let mut loaded = Vec::new();
+        // every include is relative to the theme it is in
+        let load = |include: &str, including: &[String]| {
+            loaded.push((include.to_string(), including.to_vec()));
+            let mut dir = Vec::new();
+            for path in including.iter().map(String::as_str).chain([include]) {
+                dir.pop();
+                for part in path.split('/') {
+                    match part {
+                        "." => {}
+                        ".." => _ = dir.pop(),
+                        part => dir.push(part),
+                    }
+                }
+            }
+            let json = themes
+                .get(&*dir.join("/"))
+                .ok_or(std::io::ErrorKind::NotFound)?;
+            Ok(json.to_string())
+        };
+
+        let theme =
+            Theme::from_json_with_loader(r#"{ "include": "themes/base.json" }"#, load).unwrap();
+        assert_eq!(theme.style_for(&["keyword"]).foreground, rgb(0, 0, 1));
+        assert_eq!(theme.style_for(&["string"]).foreground, rgb(0, 0, 2));
+
+        let base = "themes/base.json".to_string();
+        let colors = "./common/colors.json".to_string();
+        assert_eq!(
+            loaded,
+            [
+                (base.clone(), vec![]),
+                (colors.clone(), vec![base.clone()]),
+                ("../shared.json".to_string(), vec![base, colors]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_include_cycles() {
+        let load = |include: &str, _: &[String]| Ok(format!(r#"{{ "include": "{include}" }}"#));
+
+        let err =
+            Theme::from_json_with_loader(r#"{ "include": "./self.json" }"#, load).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to load included theme \"./self.json\""
+        );
+        let mut source = std::error::Error::source(&err);
+        while let Some(inner) = source.and_then(std::error::Error::source) {
+            source = Some(inner);
+        }
+        assert_eq!(
+            source.unwrap().to_string(),
+            "theme includes itself through \"./self.json\""
+        );
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        let rgba = |r, g, b, a| Some(Rgba { r, g, b, a });
+        assert_eq!(Rgba::from_hex("#12aBcD"), rgba(0x12, 0xab, 0xcd, 0xff));
+        assert_eq!(Rgba::from_hex("#12abcd80"), rgba(0x12, 0xab, 0xcd, 0x80));
+        assert_eq!(Rgba::from_hex("#1aF"), rgba(0x11, 0xaa, 0xff, 0xff));
+        assert_eq!(Rgba::from_hex("#1af8"), rgba(0x11, 0xaa, 0xff, 0x88));
+
+        for invalid in [
+            "",
+            "#",
+            "12abcd",
+            "#12abc",
+            "#12abcdef0",
+            "#12abcg",
+            "#1é",
+            "#+1a",
+        ] {
+            assert_eq!(Rgba::from_hex(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn composites_translucent_colors() {
+        let white = Rgba::from_hex("#ffffff").unwrap();
+        let black = Rgba::from_hex("#000000").unwrap();
+        let style = Style {
+            foreground: Rgba::from_hex("#ff000080"),
+            background: Rgba::from_hex("#ffffff80"),
+            font_style: FontStyle::default(),
+        };
+
+        let composited = style.over(Some(black));
+        assert_eq!(composited.background, rgb(0x80, 0x80, 0x80));
+        assert_eq!(composited.foreground, rgb(0xc0, 0x40, 0x40));
+        assert_eq!(style.over(None), style);
+
+        assert_eq!(white.over(black), white);
+        assert_eq!(Rgba { a: 0, ..white }.over(black), black);
+    }
+
+    #[test]
+    fn converts_styles_to_ansi() {
+        let style = Style {
+            foreground: Rgba::from_hex("#ff0000"),
+            background: Rgba::from_hex("#808080"),
+            font_style: FontStyle::parse("bold underline"),
+        };
+
+        assert_eq!(
+            style.to_ansi_truecolor(),
+            "\x1b[0;38;2;255;0;0;48;2;128;128;128;1;4m"
+        );
+        assert_eq!(style.to_ansi_256(), "\x1b[0;38;5;196;48;5;244;1;4m");
+        assert_eq!(Style::default().to_ansi_256(), "\x1b[0m");
+    }
+
+    #[test]
+    fn picks_the_closest_palette_colors() {
+        let palette = |hex| Rgba::from_hex(hex).unwrap().to_ansi_256();
+        assert_eq!(palette("#000000"), 16);
+        assert_eq!(palette("#ffffff"), 231);
+        assert_eq!(palette("#5f87af"), 67);
+        assert_eq!(palette("#080808"), 232);
+        assert_eq!(palette("#eeeeee"), 255);
+        assert_eq!(palette("#767676"), 243);
+    }
+
+    #[test]
+    fn reports_invalid_themes() {
+        let err = Theme::from_json(r#"{ "tokenColors": 3 }"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to deserialize theme json at \"tokenColors\""
+        );
+    }
+}
@@ -0,0 +1,2185 @@
+use crate::selector::{self, Priority};
+use crate::syntax_compiler::compile::{
+    Captures, CompiledRegex, PartialRegexId, RegexId, RegexRef, Rule, RuleId, RuleIdOrReference,
+    ScopeId, SyntaxId, SyntaxSet,
+};
+use crate::syntax_compiler::engine::{RegexEngine, Region};
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// the matching loop closely follows
+// https://github.com/microsoft/vscode-textmate/blob/f03a6a8790af81372d0e81facae75554ec5e97ef/src/grammar/tokenizeString.ts
+
+/// Interned scopes active over a token, outermost first.
+///
+/// Stacks up to 8 scopes deep, which covers most tokens, are stored inline
+/// without allocating. Resolve the ids with [`Grammar::resolve_scope`].
+///
+/// [`Grammar::resolve_scope`]: crate::Grammar::resolve_scope
+pub type ScopeStack = SmallVec<[ScopeId; 8]>;
+
+/// A run of a line that has the same scopes applied to it.
+///
+/// The tokens of a line cover it without gaps or overlaps, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Token {
+    /// Range of the token in the line, in bytes unless
+    /// [`TokenizerConfig::offset_mode`] says otherwise.
+    pub range: Range<usize>,
+    /// Scopes active over the token, outermost first. The outermost is always
+    /// the grammar's scope name, e.g. `source.rust`.
+    pub scopes: Vec<String>,
+}
+
+impl Token {
+    /// The scopes of the token as string slices, outermost first.
+    pub fn scope_names(&self) -> Vec<&str> {
+        self.scopes.iter().map(String::as_str).collect()
+    }
+
+    /// The innermost scope of the token, e.g. `string.quoted.double`.
+    ///
+    /// Only empty for tokens made up by hand, the outermost scope is the grammar's
+    /// scope name.
+    pub fn innermost_scope(&self) -> Option<&str> {
+        self.scopes.last().map(String::as_str)
+    }
+
+    /// Whether any scope of the token is `prefix` or starts with it followed by a
+    /// dot, like in scope selectors: `string` matches `string.quoted.double`, but
+    /// not `strings`.
+    pub fn has_scope_prefix(&self, prefix: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| selector::scope_matches(prefix, scope))
+    }
+
+    /// The text of the token, given the line it was tokenized from.
+    ///
+    /// Panics unless the range is in bytes, the default
+    /// [`TokenizerConfig::offset_mode`], and fits into `line`.
+    pub fn text<'l>(&self, line: &'l str) -> &'l str {
+        &line[self.range.clone()]
+    }
+
+    /// Merges runs of adjacent tokens with the same scopes into one token each.
+    ///
+    /// Captures and patterns that match little at a time leave many such runs,
+    /// which renderers would otherwise emit as separate spans.
+    pub fn coalesce(tokens: Vec<Token>) -> Vec<Token> {
+        let mut coalesced: Vec<Token> = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match coalesced.last_mut() {
+                Some(last)
+                    if last.range.end == token.range.start && last.scopes == token.scopes =>
+                {
+                    last.range.end = token.range.end;
+                }
+                _ => coalesced.push(token),
+            }
+        }
+        coalesced
+    }
+}
+
+/// A begin/end or begin/while region of a text starting or ending, e.g. to
+/// build fold regions; see [`Grammar::tokenize_events`].
+///
+/// [`Grammar::tokenize_events`]: crate::Grammar::tokenize_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeEvent {
+    /// A region with this scope starts here.
+    Push(String, Position),
+    /// The innermost open region, with this scope, ends here.
+    Pop(String, Position),
+}
+
+/// A position in a text, in the unit of [`TokenizerConfig::offset_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    /// The index of the line.
+    pub line: usize,
+    /// The offset into the line.
+    pub offset: usize,
+}
+
+/// Limits that protect tokenization from pathological input, e.g. minified
+/// files or regexes that backtrack catastrophically.
+///
+/// When a limit is hit, the rest of the line becomes a single token with the
+/// scopes that were active at that point. All limits but `max_scope_depth` are
+/// off by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenizerConfig {
+    /// Lines longer than this many bytes are not tokenized at all.
+    pub max_line_len: Option<usize>,
+    /// The most backtracking steps a single regex match may take.
+    ///
+    /// Only Oniguruma counts them. With the `backend-fancy` feature this is
+    /// ignored, and fancy-regex's own backtracking limit applies instead.
+    pub max_match_steps: Option<u32>,
+    /// How long a single line may take; checked between matches, so a single
+    /// slow regex can overrun it, which `max_match_steps` guards against.
+    pub time_limit: Option<Duration>,
+    /// The most rules that can be open at once, counting the grammar itself.
+    ///
+    /// Begin matches that would open more are not entered; instead they get the
+    /// `invalid.illegal.scope-depth-exceeded` scope and tokenization carries on
+    /// in the current rule. Defaults to 1000, far deeper than real code nests.
+    pub max_scope_depth: Option<usize>,
+    /// Rules with one of these names are skipped, e.g. to turn off an expensive
+    /// embedded language. Disabling a begin rule also disables everything that
+    /// would have matched inside it.
+    pub disabled_scopes: HashSet<String>,
+    /// The unit of token ranges. Matching always works on bytes; ranges are
+    /// converted when tokens are returned.
+    pub offset_mode: OffsetMode,
+    /// Whether the leading whitespace of a line is a token of its own, e.g. for
+    /// renderers that draw indentation. The token only has the grammar's base
+    /// scope, even inside a multi-line comment or string, and is a single token
+    /// however many tokens the whitespace spanned. Off by default.
+    pub split_leading_whitespace: bool,
+}
+
+impl Default for TokenizerConfig {
+    fn default() -> Self {
+        Self {
+            max_line_len: None,
+            max_match_steps: None,
+            time_limit: None,
+            max_scope_depth: Some(1000),
+            disabled_scopes: HashSet::new(),
+            offset_mode: OffsetMode::Byte,
+            split_leading_whitespace: false,
+        }
+    }
+}
+
+/// The unit token ranges are counted in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OffsetMode {
+    /// Bytes of the UTF-8 line, which can slice the line directly.
+    #[default]
+    Byte,
+    /// UTF-16 code units, as used by VS Code and the Language Server Protocol.
+    Utf16,
+    /// Unicode scalar values, i.e. `char`s; a character outside the Basic
+    /// Multilingual Plane counts as one, where it's two UTF-16 code units.
+    Char,
+}
+
+impl OffsetMode {
+    /// Converts an offset into `line` from this unit into another.
+    ///
+    /// Returns `None` if the offset is past the end of the line or in the middle
+    /// of a character.
+    pub fn convert(self, line: &str, offset: usize, to: OffsetMode) -> Option<usize> {
+        let mut from_offset = 0;
+        let mut to_offset = 0;
+        let mut chars = line.chars();
+        while from_offset < offset {
+            let char = chars.next()?;
+            from_offset += self.char_len(char);
+            to_offset += to.char_len(char);
+        }
+        (from_offset == offset).then_some(to_offset)
+    }
+
+    fn char_len(self, char: char) -> usize {
+        match self {
+            OffsetMode::Byte => char.len_utf8(),
+            OffsetMode::Utf16 => char.len_utf16(),
+            OffsetMode::Char => 1,
+        }
+    }
+
+    // the length of `text` in this unit
+    pub(crate) fn len_of(self, text: &str) -> usize {
+        match self {
+            OffsetMode::Byte => text.len(),
+            OffsetMode::Utf16 => text.encode_utf16().count(),
+            OffsetMode::Char => text.chars().count(),
+        }
+    }
+
+    // converts the byte ranges of a line's tokens, which are in order, in a
+    // single pass over the line
+    fn convert_ranges(self, line: &str, tokens: &mut [(Range<usize>, ScopeStack)]) {
+        if self == OffsetMode::Byte {
+            return;
+        }
+        let (mut byte, mut offset) = (0, 0);
+        let mut convert = |pos: usize| {
+            offset += self.len_of(&line[byte..pos]);
+            byte = pos;
+            offset
+        };
+        for (range, _) in tokens {
+            *range = convert(range.start)..convert(range.end);
+        }
+    }
+}
+
+/// Tokenizer state carried from the end of one line to the start of the next.
+///
+/// The first line of a document is tokenized starting from
+/// [`TokenizerState::default()`].
+///
+/// Two states are equal when the next line tokenizes the same from either of
+/// them, so a line whose end state didn't change after an edit means the lines
+/// after it don't change either. The hash only depends on the grammar and the
+/// text tokenized so far, so it can key a cache of line start states.
+#[derive(Debug, Clone, Default)]
+pub struct TokenizerState {
+    // bottom frame is the grammar's root rule; empty until the first line is tokenized
+    stack: Vec<StackFrame>,
+    end_regexes: RegexCache,
+}
+
+impl TokenizerState {
+    /// Like [`TokenizerState::default()`], but with a custom bound on the number
+    /// of cached end patterns.
+    ///
+    /// End patterns that refer to what their begin pattern captured (e.g. the
+    /// delimiter of a heredoc) have to be compiled for every match; the most
+    /// recently used ones are kept, so that repeated delimiters compile only once.
+    /// A capacity of 0 disables the cache.
+    pub fn with_regex_cache_capacity(capacity: usize) -> Self {
+        Self {
+            stack: Vec::new(),
+            end_regexes: RegexCache::new(capacity),
+        }
+    }
+}
+
+// the regex cache only affects speed, so it's left out
+impl PartialEq for TokenizerState {
+    fn eq(&self, other: &Self) -> bool {
+        self.stack == other.stack
+    }
+}
+
+impl Eq for TokenizerState {}
+
+impl Hash for TokenizerState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.stack.hash(state);
+    }
+}
+
+const DEFAULT_REGEX_CACHE_CAPACITY: usize = 32;
+
+// end and while patterns with substituted back references, most recently used first
+#[derive(Debug, Clone)]
+struct RegexCache {
+    capacity: usize,
+    entries: Vec<((SyntaxId, PartialRegexId, String), Arc<CompiledRegex>)>,
+}
+
+impl RegexCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Vec::new(),
+        }
+    }
+
+    fn get_or_insert(&mut self, key: (SyntaxId, PartialRegexId, String)) -> Arc<CompiledRegex> {
+        if let Some(idx) = self.entries.iter().position(|(cached, _)| *cached == key) {
+            let entry = self.entries.remove(idx);
+            let regex = entry.1.clone();
+            self.entries.insert(0, entry);
+            return regex;
+        }
+
+        let regex = Arc::new(CompiledRegex::new(key.2.clone()));
+        if self.capacity > 0 {
+            self.entries.truncate(self.capacity - 1);
+            self.entries.insert(0, (key, regex.clone()));
+        }
+        regex
+    }
+}
+
+impl Default for RegexCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REGEX_CACHE_CAPACITY)
+    }
+}
+
+// a begin/end or begin/while rule that has been entered and not yet exited
+#[derive(Debug, Clone)]
+struct StackFrame {
+    syntax_id: SyntaxId,
+    rule_id: RuleId,
+    // search position at which the rule was pushed, used to detect grammars
+    // that would push and pop forever without advancing
+    enter_pos: Option<usize>,
+    // where `\G` could match before the rule was pushed, restored when it's popped
+    anchor_pos: Option<usize>,
+    // whether the begin match reached the end of the line, in which case `\G`
+    // matches at the start of the next one
+    begin_captured_eol: bool,
+    // the end or while pattern with the begin captures substituted, if it refers to any
+    end: Option<Arc<CompiledRegex>>,
+    // scopes of the begin and end matches, i.e. with the rule's name
+    name_scopes: ScopeStack,
+    // scopes of everything in between, i.e. with the rule's name and contentName
+    scopes: ScopeStack,
+}
+
+impl StackFrame {
+    fn end_pattern(&self) -> Option<&str> {
+        self.end.as_ref().map(|end| end.get().pattern())
+    }
+}
+
+// positions are reset at the end of every line, so they're left out
+impl PartialEq for StackFrame {
+    fn eq(&self, other: &Self) -> bool {
+        self.syntax_id == other.syntax_id
+            && self.rule_id == other.rule_id
+            && self.begin_captured_eol == other.begin_captured_eol
+            && self.end_pattern() == other.end_pattern()
+            && self.name_scopes == other.name_scopes
+            && self.scopes == other.scopes
+    }
+}
+
+impl Eq for StackFrame {}
+
+impl Hash for StackFrame {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.syntax_id.hash(state);
+        self.rule_id.hash(state);
+        self.begin_captured_eol.hash(state);
+        self.end_pattern().hash(state);
+        self.name_scopes.hash(state);
+        self.scopes.hash(state);
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum CandidateRegex {
+    Regex(RegexId),
+    Partial(PartialRegexId),
+}
+
+#[derive(Debug, Copy, Clone)]
+enum MatchedRule {
+    Rule(SyntaxId, RuleId),
+    // the end pattern of the rule on top of the stack
+    End,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Candidate {
+    // the grammar owning the regex
+    syntax_id: SyntaxId,
+    regex: CandidateRegex,
+    rule: MatchedRule,
+}
+
+struct Match {
+    rule: MatchedRule,
+    start: usize,
+    end: usize,
+    captures: Region,
+}
+
+#[derive(Default)]
+struct LineTokens {
+    tokens: Vec<(Range<usize>, ScopeStack)>,
+    last_end: usize,
+    // where regions were entered and left, if anyone asked
+    regions: Option<Vec<RegionChange>>,
+}
+
+// A begin/end or begin/while rule pushed or popped at a byte offset of the line.
+// Entering gives the rule's name, or its contentName if it has none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RegionChange {
+    Enter(usize, Option<ScopeId>),
+    Leave(usize),
+}
+
+impl LineTokens {
+    fn produce(&mut self, scopes: &[ScopeId], end: usize) {
+        if end <= self.last_end {
+            return;
+        }
+
+        self.tokens
+            .push((self.last_end..end, ScopeStack::from_slice(scopes)));
+        self.last_end = end;
+    }
+
+    // rules pushed while tokenizing a capture end with it, so they aren't
+    // regions of the text
+    fn region(&mut self, line: &Line, change: RegionChange) {
+        if let Some(regions) = &mut self.regions
+            && line.capture_depth == 0
+        {
+            regions.push(change);
+        }
+    }
+
+    // makes the first `indent` bytes a single token with `scopes`, cutting short
+    // or dropping the tokens they were part of
+    fn split_indent(&mut self, indent: usize, scopes: &ScopeStack) {
+        if indent == 0 {
+            return;
+        }
+        let covered = self
+            .tokens
+            .partition_point(|(range, _)| range.end <= indent);
+        self.tokens.drain(..covered);
+        if let Some((range, _)) = self.tokens.first_mut() {
+            range.start = indent;
+        }
+        self.tokens.insert(0, (0..indent, scopes.clone()));
+    }
+}
+
+// a limit of the tokenizer config was hit
+struct LimitExceeded;
+
+// captures tokenized with their own patterns can contain captures tokenized with
+// theirs; past this depth they only get their name, so that a capture including
+// its own rule can't recurse forever
+const MAX_CAPTURE_DEPTH: usize = 16;
+
+// the text being tokenized and the limits for it
+struct Line<'l> {
+    text: &'l str,
+    deadline: Option<Instant>,
+    // how many captures deep the text is, see `MAX_CAPTURE_DEPTH`
+    capture_depth: usize,
+}
+
+pub(crate) struct Tokenizer<'a> {
+    syntax_set: &'a SyntaxSet,
+    // the grammar being tokenized; rules of other grammars are reached through includes
+    root: SyntaxId,
+    config: Cow<'a, TokenizerConfig>,
+    // `config.disabled_scopes` as ids of the set
+    disabled_scopes: &'a [ScopeId],
+}
+
+impl<'a> Tokenizer<'a> {
+    pub(crate) fn new(syntax_set: &'a SyntaxSet, root: SyntaxId) -> Self {
+        Self {
+            syntax_set,
+            root,
+            config: Cow::Owned(TokenizerConfig::default()),
+            disabled_scopes: &[],
+        }
+    }
+
+    pub(crate) fn with_config(
+        self,
+        config: &'a TokenizerConfig,
+        disabled_scopes: &'a [ScopeId],
+    ) -> Self {
+        Self {
+            config: Cow::Borrowed(config),
+            disabled_scopes,
+            ..self
+        }
+    }
+
+    fn rule(&self, syntax_id: SyntaxId, rule_id: RuleId) -> &'a Rule {
+        self.syntax_set.syntax(syntax_id).rule(rule_id)
+    }
+
+    pub(crate) fn tokenize_line(&self, line: &str, state: &mut TokenizerState) -> Vec<Token> {
+        self.tokenize(line, state)
+            .into_iter()
+            .map(|(range, scopes)| Token {
+                range,
+                scopes: scopes
+                    .into_iter()
+                    .map(|scope| self.syntax_set.scopes.resolve(scope).to_string())
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn tokenize_to_scopes(
+        &self,
+        line: &str,
+        state: &mut TokenizerState,
+    ) -> Vec<(Range<usize>, Vec<&'a str>)> {
+        let scopes = &self.syntax_set.scopes;
+        self.tokenize(line, state)
+            .into_iter()
+            .map(|(range, ids)| {
+                (
+                    range,
+                    ids.into_iter().map(|id| scopes.resolve(id)).collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn tokenize(
+        &self,
+        line: &str,
+        state: &mut TokenizerState,
+    ) -> Vec<(Range<usize>, ScopeStack)> {
+        let mut tokens = LineTokens::default();
+        self.tokenize_into(line, state, &mut tokens);
+        tokens.tokens
+    }
+
+    // Like `tokenize`, but also says where regions were entered and left; the
+    // offsets are in bytes.
+    pub(crate) fn tokenize_regions(
+        &self,
+        line: &str,
+        state: &mut TokenizerState,
+    ) -> (Vec<(Range<usize>, ScopeStack)>, Vec<RegionChange>) {
+        let mut tokens = LineTokens {
+            regions: Some(Vec::new()),
+            ..Default::default()
+        };
+        self.tokenize_into(line, state, &mut tokens);
+        (tokens.tokens, tokens.regions.unwrap_or_default())
+    }
+
+    fn tokenize_into(&self, line: &str, state: &mut TokenizerState, tokens: &mut LineTokens) {
+        let stack = &mut state.stack;
+        let end_regexes = &mut state.end_regexes;
+        if stack.is_empty() {
+            // every token is in the grammar's base scope, e.g. `source.rust`
+            let root = self.syntax_set.syntax(self.root);
+            let base_scopes: ScopeStack = [root.scope_id].into_iter().collect();
+            stack.push(StackFrame {
+                syntax_id: self.root,
+                rule_id: root.root_rule_id(),
+                enter_pos: None,
+                anchor_pos: None,
+                begin_captured_eol: false,
+                end: None,
+                name_scopes: base_scopes.clone(),
+                scopes: base_scopes,
+            });
+        }
+
+        if self.config.max_line_len.is_some_and(|max| line.len() > max) {
+            tokens.produce(&stack.last().unwrap().scopes, line.len());
+            self.config
+                .offset_mode
+                .convert_ranges(line, &mut tokens.tokens);
+            return;
+        }
+        let line = Line {
+            text: line,
+            deadline: self.config.time_limit.map(|limit| Instant::now() + limit),
+            capture_depth: 0,
+        };
+
+        let mut pos = 0;
+        // the position right after the last begin match, the only one where `\G` can match
+        let mut anchor_pos = stack.last().unwrap().begin_captured_eol.then_some(0);
+        self.check_while_conditions(&line, stack, end_regexes, tokens, &mut pos, &mut anchor_pos);
+        self.tokenize_stack(&line, stack, end_regexes, tokens, (pos, anchor_pos));
+
+        // positions are only meaningful within a line
+        for frame in stack.iter_mut() {
+            frame.enter_pos = None;
+            frame.anchor_pos = None;
+        }
+
+        if self.config.split_leading_whitespace {
+            let indent = line.text.len() - line.text.trim_start().len();
+            // the bottom of the stack only has the grammar's base scope
+            tokens.split_indent(indent, &stack[0].scopes);
+        }
+
+        self.config
+            .offset_mode
+            .convert_ranges(line.text, &mut tokens.tokens);
+    }
+
+    // Tokenizes the line from `pos` on, starting in the rule on top of the stack.
+    fn tokenize_stack(
+        &self,
+        line: &Line,
+        stack: &mut Vec<StackFrame>,
+        end_regexes: &mut RegexCache,
+        tokens: &mut LineTokens,
+        (mut pos, mut anchor_pos): (usize, Option<usize>),
+    ) {
+        loop {
+            if line
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                break;
+            }
+
+            let top = stack.last().unwrap();
+            let allow_anchor = anchor_pos == Some(pos);
+            // when a limit is hit, the rest of the line gets the current scopes
+            let Ok(Some(found)) = self.find_next_match(top, line.text, pos, allow_anchor) else {
+                break;
+            };
+
+            tokens.produce(&top.scopes, found.start);
+            let has_advanced = found.end > pos;
+
+            match found.rule {
+                MatchedRule::End => {
+                    if let Rule::BeginEnd(rule) = self.rule(top.syntax_id, top.rule_id) {
+                        let captures = rule.end_captures.as_ref();
+                        self.handle_captures(
+                            line,
+                            end_regexes,
+                            (top.syntax_id, captures),
+                            &top.name_scopes,
+                            &found,
+                            tokens,
+                        );
+                    }
+                    tokens.produce(&top.name_scopes, found.end);
+                    let popped = stack.pop().unwrap();
+                    anchor_pos = popped.anchor_pos;
+
+                    if !has_advanced && popped.enter_pos == Some(pos) {
+                        // the rule was pushed and popped without advancing; assume the
+                        // grammar author meant to stay in the rule and give up on the line
+                        stack.push(popped);
+                        break;
+                    }
+                    tokens.region(line, RegionChange::Leave(found.end));
+                }
+                MatchedRule::Rule(syntax_id, rule_id) => match self.rule(syntax_id, rule_id) {
+                    Rule::Match(rule) => {
+                        let scopes = push_scope(&top.scopes, rule.name);
+                        let captures = rule.captures.as_ref();
+                        self.handle_captures(
+                            line,
+                            end_regexes,
+                            (syntax_id, captures),
+                            &scopes,
+                            &found,
+                            tokens,
+                        );
+                        tokens.produce(&scopes, found.end);
+
+                        if !has_advanced {
+                            // neither advancing nor changing the stack, bail out
+                            if stack.len() > 1 {
+                                stack.pop();
+                                tokens.region(line, RegionChange::Leave(pos));
+                            }
+                            break;
+                        }
+                    }
+                    Rule::BeginEnd(_) | Rule::BeginWhile(_)
+                        if self
+                            .config
+                            .max_scope_depth
+                            .is_some_and(|max| stack.len() >= max) =>
+                    {
+                        let scopes =
+                            push_scope(&top.scopes, Some(self.syntax_set.depth_exceeded_scope));
+                        tokens.produce(&scopes, found.end);
+
+                        if !has_advanced {
+                            break;
+                        }
+                    }
+                    rule @ (Rule::BeginEnd(_) | Rule::BeginWhile(_)) => {
+                        let name_scopes = push_scope(&top.scopes, rule.name());
+                        let captures = match rule {
+                            Rule::BeginEnd(rule) => rule.begin_captures.as_ref(),
+                            Rule::BeginWhile(rule) => rule.begin_captures.as_ref(),
+                            _ => None,
+                        };
+                        self.handle_captures(
+                            line,
+                            end_regexes,
+                            (syntax_id, captures),
+                            &name_scopes,
+                            &found,
+                            tokens,
+                        );
+                        tokens.produce(&name_scopes, found.end);
+
+                        if !has_advanced && pushes_same_rule(stack, syntax_id, rule_id, pos) {
+                            // the same rule was already pushed at this position
+                            break;
+                        }
+
+                        let end = match rule {
+                            Rule::BeginEnd(rule) => self.end_with_back_references(
+                                (syntax_id, rule.begin, rule.end),
+                                line.text,
+                                &found,
+                                end_regexes,
+                            ),
+                            Rule::BeginWhile(rule) => self.end_with_back_references(
+                                (syntax_id, rule.begin, rule.while_),
+                                line.text,
+                                &found,
+                                end_regexes,
+                            ),
+                            _ => None,
+                        };
+                        let scope = rule.name().or(rule.content_name());
+                        tokens.region(line, RegionChange::Enter(found.start, scope));
+                        stack.push(StackFrame {
+                            syntax_id,
+                            rule_id,
+                            enter_pos: Some(pos),
+                            anchor_pos,
+                            begin_captured_eol: found.end == line.text.len(),
+                            end,
+                            scopes: push_scope(&name_scopes, rule.content_name()),
+                            name_scopes,
+                        });
+                        anchor_pos = Some(found.end);
+                    }
+                    Rule::IncludeOnly(_) => {
+                        unreachable!("only rules with a regex can match")
+                    }
+                },
+            }
+
+            pos = found.end;
+        }
+
+        tokens.produce(&stack.last().unwrap().scopes, line.text.len());
+    }
+
+    // Begin/while rules stay on the stack only as long as every following line
+    // matches their while pattern. The patterns are checked outermost first, each
+    // continuing where the previous one matched; the first rule whose pattern
+    // doesn't match is popped together with everything pushed after it.
+    fn check_while_conditions(
+        &self,
+        line: &Line,
+        stack: &mut Vec<StackFrame>,
+        end_regexes: &mut RegexCache,
+        tokens: &mut LineTokens,
+        pos: &mut usize,
+        anchor_pos: &mut Option<usize>,
+    ) {
+        for idx in 0..stack.len() {
+            let frame = &stack[idx];
+            let Rule::BeginWhile(rule) = self.rule(frame.syntax_id, frame.rule_id) else {
+                continue;
+            };
+
+            let regex = match &frame.end {
+                Some(while_) => while_.get(),
+                None => self.regex(frame.syntax_id, CandidateRegex::Partial(rule.while_)),
+            };
+            let allow_anchor = *anchor_pos == Some(*pos);
+            let found = match self.search(regex, line.text, *pos, allow_anchor) {
+                Ok(Some((start, end, captures))) => Match {
+                    rule: MatchedRule::End,
+                    start,
+                    end,
+                    captures,
+                },
+                Ok(None) => {
+                    for _ in idx..stack.len() {
+                        tokens.region(line, RegionChange::Leave(*pos));
+                    }
+                    stack.truncate(idx);
+                    return;
+                }
+                // keep the rules, the line is going to be left untokenized anyway
+                Err(LimitExceeded) => return,
+            };
+
+            tokens.produce(&frame.scopes, found.start);
+            let captures = rule.while_captures.as_ref();
+            self.handle_captures(
+                line,
+                end_regexes,
+                (frame.syntax_id, captures),
+                &frame.scopes,
+                &found,
+                tokens,
+            );
+            tokens.produce(&frame.scopes, found.end);
+            *anchor_pos = Some(found.end);
+            *pos = (*pos).max(found.end);
+        }
+    }
+
+    // Splits the match into tokens by its capture groups. Groups are applied in
+    // order, each nested in the previous ones that are still open, so that e.g.
+    // group 2 inside group 1 gets both scopes. Groups whose rule has patterns are
+    // tokenized with them, as if the line ended where the group does.
+    fn handle_captures(
+        &self,
+        line: &Line,
+        end_regexes: &mut RegexCache,
+        (syntax_id, captures): (SyntaxId, Option<&Captures>),
+        scopes: &[ScopeId],
+        found: &Match,
+        tokens: &mut LineTokens,
+    ) {
+        let Some(captures) = captures else {
+            return;
+        };
+
+        // scopes of the groups that are open, and where they end
+        let mut open: Vec<(ScopeStack, usize)> = Vec::new();
+        for (idx, rule_id) in captures.0.iter().enumerate() {
+            let Some(rule_id) = rule_id else {
+                continue;
+            };
+            let Some((start, end)) = found.captures.pos(idx) else {
+                continue;
+            };
+            if start == end {
+                continue;
+            }
+            // groups in a lookahead can capture past the match
+            if start > found.end {
+                break;
+            }
+
+            while let Some((group_scopes, group_end)) = open.last() {
+                if *group_end > start {
+                    break;
+                }
+                tokens.produce(group_scopes, *group_end);
+                open.pop();
+            }
+
+            let outer = open.last().map_or(scopes, |(group_scopes, _)| group_scopes);
+            tokens.produce(outer, start);
+            let rule = self.rule(syntax_id, *rule_id);
+            let name_scopes = push_scope(outer, rule.name());
+
+            match rule {
+                Rule::IncludeOnly(capture_rule)
+                    if !capture_rule.patterns.is_empty()
+                        && line.capture_depth < MAX_CAPTURE_DEPTH =>
+                {
+                    let captured = Line {
+                        text: &line.text[..end],
+                        deadline: line.deadline,
+                        capture_depth: line.capture_depth + 1,
+                    };
+                    let mut stack = vec![StackFrame {
+                        syntax_id,
+                        rule_id: *rule_id,
+                        enter_pos: Some(start),
+                        anchor_pos: None,
+                        begin_captured_eol: false,
+                        end: None,
+                        scopes: push_scope(&name_scopes, rule.content_name()),
+                        name_scopes,
+                    }];
+                    let start = (start, Some(start));
+                    self.tokenize_stack(&captured, &mut stack, end_regexes, tokens, start);
+                }
+                _ if rule.name().is_some() => open.push((name_scopes, end)),
+                _ => {}
+            }
+        }
+
+        while let Some((group_scopes, group_end)) = open.pop() {
+            tokens.produce(&group_scopes, group_end);
+        }
+    }
+
+    fn find_next_match(
+        &self,
+        frame: &StackFrame,
+        line: &str,
+        pos: usize,
+        allow_anchor: bool,
+    ) -> Result<Option<Match>, LimitExceeded> {
+        // as in vscode-textmate, injected patterns compete with the grammar's own
+        // ones by position; `L:` injections win ties with them, the others lose
+        let (before, after) = self.collect_injected_candidates(&frame.scopes);
+        let mut candidates = before;
+        candidates.extend(self.collect_candidates(frame.syntax_id, frame.rule_id));
+        candidates.extend(after);
+
+        let mut best: Option<Match> = None;
+        for candidate in candidates {
+            let regex = match (candidate.rule, &frame.end) {
+                (MatchedRule::End, Some(end)) => end.get(),
+                _ => self.regex(candidate.syntax_id, candidate.regex),
+            };
+            let Some((start, end, region)) = self.search(regex, line, pos, allow_anchor)? else {
+                continue;
+            };
+
+            // leftmost match wins, ties are broken by pattern order
+            if best.as_ref().is_none_or(|best| start < best.start) {
+                best = Some(Match {
+                    rule: candidate.rule,
+                    start,
+                    end,
+                    captures: region,
+                });
+                // nothing can match further left
+                if start == pos {
+                    break;
+                }
+            }
+        }
+
+        Ok(best)
+    }
+
+    // like vscode-textmate, patterns the engine rejects simply never match
+    fn search(
+        &self,
+        regex: RegexRef,
+        line: &str,
+        pos: usize,
+        allow_anchor: bool,
+    ) -> Result<Option<(usize, usize, Region)>, LimitExceeded> {
+        let Ok(regex) = regex.compiled_for(allow_anchor) else {
+            return Ok(None);
+        };
+
+        let found = regex
+            .search_from(line, pos, self.config.max_match_steps)
+            .map_err(|_| LimitExceeded)?;
+        Ok(found.and_then(|region| {
+            let (start, end) = region.pos(0)?;
+            Some((start, end, region))
+        }))
+    }
+
+    // Patterns of the tokenized grammar's injections, and of grammars injecting
+    // into it, whose selector matches the scopes: those to go before the grammar's
+    // own patterns (`L:`) and those to go after them. Each are ordered by priority,
+    // then the most specific selector first. Selectors see the grammar's scope name
+    // at the bottom of the stack, so that e.g. `source.js comment` works.
+    fn collect_injected_candidates(&self, scopes: &[ScopeId]) -> (Vec<Candidate>, Vec<Candidate>) {
+        let root = self.syntax_set.syntax(self.root);
+        let injectors = self
+            .syntax_set
+            .injectors
+            .get(self.root.to_idx())
+            .map_or(&[][..], |injectors| &injectors[..]);
+        if root.injections.is_empty() && injectors.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+
+        // the stack starts with the base scope, so selectors can name the grammar
+        let scope_names: Vec<_> = scopes
+            .iter()
+            .map(|&id| self.syntax_set.scopes.resolve(id))
+            .collect();
+
+        let own = root
+            .injections
+            .iter()
+            .map(|(selector, rule_id)| (selector, self.root, *rule_id));
+        let injected_grammars = injectors.iter().filter_map(|&injector_id| {
+            let injector = self.syntax_set.syntax(injector_id);
+            let selector = &injector.injector.as_ref()?.selector;
+            Some((selector, injector_id, injector.root_rule_id()))
+        });
+        let mut injections: Vec<_> = own
+            .chain(injected_grammars)
+            .filter_map(|(selector, syntax_id, rule_id)| {
+                Some((selector.matches(&scope_names)?, syntax_id, rule_id))
+            })
+            .collect();
+        // stable, so equally specific injections keep their order
+        injections
+            .sort_by_key(|(score, _, _)| (score.priority, Reverse((score.depth, score.segments))));
+
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        let mut visited = Vec::new();
+        for (score, syntax_id, rule_id) in injections {
+            let candidates = match score.priority {
+                Priority::Left => &mut before,
+                Priority::Normal | Priority::Right => &mut after,
+            };
+            self.collect_rule(syntax_id, rule_id, &mut visited, candidates);
+        }
+        (before, after)
+    }
+
+    fn collect_candidates(&self, syntax_id: SyntaxId, rule_id: RuleId) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        let mut visited = Vec::new();
+
+        match self.rule(syntax_id, rule_id) {
+            Rule::IncludeOnly(rule) => {
+                self.collect_patterns(syntax_id, &rule.patterns, &mut visited, &mut candidates)
+            }
+            Rule::BeginEnd(rule) => {
+                let end = Candidate {
+                    syntax_id,
+                    regex: CandidateRegex::Partial(rule.end),
+                    rule: MatchedRule::End,
+                };
+                // the end pattern wins ties with the inner patterns, unless it's
+                // asked to be applied last
+                if !rule.apply_end_pattern_last {
+                    candidates.push(end);
+                }
+                // without patterns (e.g. a string that only scopes its delimiters),
+                // nothing but the end is looked for inside
+                if let Some(patterns) = &rule.patterns {
+                    self.collect_patterns(syntax_id, patterns, &mut visited, &mut candidates);
+                }
+                if rule.apply_end_pattern_last {
+                    candidates.push(end);
+                }
+            }
+            Rule::BeginWhile(rule) => {
+                if let Some(patterns) = &rule.patterns {
+                    self.collect_patterns(syntax_id, patterns, &mut visited, &mut candidates);
+                }
+            }
+            Rule::Match(_) => {}
+        }
+
+        candidates
+    }
+
+    fn collect_patterns(
+        &self,
+        syntax_id: SyntaxId,
+        patterns: &[RuleIdOrReference],
+        visited: &mut Vec<(SyntaxId, RuleId)>,
+        candidates: &mut Vec<Candidate>,
+    ) {
+        for pattern in patterns {
+            match pattern {
+                RuleIdOrReference::RuleId(rule_id) => {
+                    self.collect_rule(syntax_id, *rule_id, visited, candidates)
+                }
+                RuleIdOrReference::ForeignRuleId(foreign_syntax_id, rule_id) => {
+                    self.collect_rule(*foreign_syntax_id, *rule_id, visited, candidates)
+                }
+                RuleIdOrReference::Base => {
+                    let root_rule_id = self.syntax_set.syntax(self.root).root_rule_id();
+                    self.collect_rule(self.root, root_rule_id, visited, candidates)
+                }
+                RuleIdOrReference::Reference(_) => {
+                    unreachable!("references are resolved by the linker")
+                }
+            }
+        }
+    }
+
+    fn collect_rule(
+        &self,
+        syntax_id: SyntaxId,
+        rule_id: RuleId,
+        visited: &mut Vec<(SyntaxId, RuleId)>,
+        candidates: &mut Vec<Candidate>,
+    ) {
+        let rule = self.rule(syntax_id, rule_id);
+        if rule
+            .name()
+            .is_some_and(|name| self.disabled_scopes.contains(&name))
+        {
+            return;
+        }
+
+        match rule {
+            Rule::Match(rule) => candidates.push(Candidate {
+                syntax_id,
+                regex: CandidateRegex::Regex(rule.match_),
+                rule: MatchedRule::Rule(syntax_id, rule_id),
+            }),
+            Rule::BeginEnd(rule) => candidates.push(Candidate {
+                syntax_id,
+                regex: CandidateRegex::Regex(rule.begin),
+                rule: MatchedRule::Rule(syntax_id, rule_id),
+            }),
+            Rule::BeginWhile(rule) => candidates.push(Candidate {
+                syntax_id,
+                regex: CandidateRegex::Regex(rule.begin),
+                rule: MatchedRule::Rule(syntax_id, rule_id),
+            }),
+            Rule::IncludeOnly(rule) => {
+                // include-only rules are inlined into the including pattern list;
+                // grammars are allowed to include themselves recursively, and
+                // include cycles (`#a` includes `#b` includes `#a`) are common, so
+                // instead of being reported as errors they are cut where they close;
+                // the rules on the cycle have been collected by then anyway
+                if visited.contains(&(syntax_id, rule_id)) {
+                    return;
+                }
+                visited.push((syntax_id, rule_id));
+                self.collect_patterns(syntax_id, &rule.patterns, visited, candidates);
+            }
+        }
+    }
+
+    fn regex(&self, syntax_id: SyntaxId, regex: CandidateRegex) -> RegexRef<'a> {
+        let syntax = self.syntax_set.syntax(syntax_id);
+        match regex {
+            CandidateRegex::Regex(id) => syntax.regex(id),
+            CandidateRegex::Partial(id) => syntax.partial_regex(id),
+        }
+    }
+
+    // end patterns are shared by all matches of their rule, unless they refer to
+    // the begin captures, in which case they're substituted for every match
+    fn end_with_back_references(
+        &self,
+        (syntax_id, begin, end): (SyntaxId, RegexId, PartialRegexId),
+        line: &str,
+        found: &Match,
+        end_regexes: &mut RegexCache,
+    ) -> Option<Arc<CompiledRegex>> {
+        let end_regex = self.regex(syntax_id, CandidateRegex::Partial(end));
+        if !end_regex.has_back_references() {
+            return None;
+        }
+
+        // the begin pattern has just matched, so it compiles
+        let begin = self
+            .regex(syntax_id, CandidateRegex::Regex(begin))
+            .captured_groups(line, &found.captures)
+            .ok()?;
+        let pattern = end_regex.resolve_back_references(&begin);
+        Some(end_regexes.get_or_insert((syntax_id, end, pattern)))
+    }
+}
+
+fn push_scope(scopes: &[ScopeId], name: Option<ScopeId>) -> ScopeStack {
+    let mut scopes = ScopeStack::from_slice(scopes);
+    scopes.extend(name);
+    scopes
+}
+
+// whether pushing `rule_id` at `pos` would re-enter a rule that was already
+// entered at the same position without consuming anything
+fn pushes_same_rule(
+    stack: &[StackFrame],
+    syntax_id: SyntaxId,
+    rule_id: RuleId,
+    pos: usize,
+) -> bool {
+    stack
+        .iter()
+        .rev()
+        .take_while(|frame| frame.enter_pos == Some(pos))
+        .any(|frame| frame.syntax_id == syntax_id && frame.rule_id == rule_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_compiler::compile::{ScopeInterner, SyntaxDefinition};
+    use crate::syntax_compiler::parse;
+
+    const PARENS_TMLANG: &str = r##"{
+      "scopeName": "source.parens",
+      "patterns": [{ "include": "#expression" }],
+      "repository": {
+        "expression": {
+          "patterns": [{ "include": "#letter" }, { "include": "#paren-expression" }]
+        },
+        "letter": { "match": "a|b|c", "name": "keyword.letter" },
+        "paren-expression": {
+          "begin": "\\(",
+          "end": "\\)",
+          "name": "expression.group",
+          "patterns": [{ "include": "#expression" }]
+        }
+      }
+    }"##;
+
+    fn compile(tmlang: &str) -> SyntaxSet {
+        compile_all(&[tmlang])
+    }
+
+    fn compile_all(tmlangs: &[&str]) -> SyntaxSet {
+        let mut scopes = ScopeInterner::default();
+        let syntaxes = tmlangs
+            .iter()
+            .map(|tmlang| {
+                let parsed = parse::SyntaxDefinition::from_json(tmlang).unwrap();
+                SyntaxDefinition::compile(parsed, &mut scopes).unwrap()
+            })
+            .collect();
+        let mut syntax_set = SyntaxSet::new(syntaxes, scopes).unwrap();
+        syntax_set.link();
+        syntax_set
+    }
+
+    fn root(syntax_set: &SyntaxSet) -> Tokenizer<'_> {
+        Tokenizer::new(syntax_set, SyntaxId::from_idx(0))
+    }
+
+    // every token starts with the grammar's base scope, which is left out to keep
+    // expectations readable
+    fn inner_scopes(token: &Token) -> String {
+        let (_base, inner) = token
+            .scopes
+            .split_first()
+            .expect("tokens have a base scope");
+        inner.join(" ")
+    }
+
+    // scopes are joined with spaces to keep expectations readable
+    fn tokenize<'l>(tmlang: &str, line: &'l str) -> Vec<(&'l str, String)> {
+        let syntax_set = compile(tmlang);
+
+        root(&syntax_set)
+            .tokenize_line(line, &mut TokenizerState::default())
+            .into_iter()
+            .map(|token| (token.text(line), inner_scopes(&token)))
+            .collect()
+    }
+
+    #[test]
+    fn tokens_cover_the_line() {
+        let line = "x (a (b) y) c";
+        let syntax_set = compile(PARENS_TMLANG);
+        let tokens = root(&syntax_set).tokenize_line(line, &mut TokenizerState::default());
+
+        assert_eq!(tokens.first().unwrap().range.start, 0);
+        assert_eq!(tokens.last().unwrap().range.end, line.len());
+        for window in tokens.windows(2) {
+            assert_eq!(window[0].range.end, window[1].range.start);
+        }
+    }
+
+    #[test]
+    fn tokens_expose_their_text_and_scopes() {
+        let line = "x (a)";
+        let syntax_set = compile(PARENS_TMLANG);
+        let tokens = root(&syntax_set).tokenize_line(line, &mut TokenizerState::default());
+
+        let letter = &tokens[2];
+        assert_eq!(letter.text(line), "a");
+        assert_eq!(
+            letter.scope_names(),
+            ["source.parens", "expression.group", "keyword.letter"]
+        );
+    }
+
+    #[test]
+    fn answers_scope_queries() {
+        let token = Token {
+            range: 0..1,
+            scopes: vec!["source.q".to_string(), "string.quoted.double".to_string()],
+        };
+        assert_eq!(token.innermost_scope(), Some("string.quoted.double"));
+        assert!(token.has_scope_prefix("string"));
+        assert!(token.has_scope_prefix("string.quoted"));
+        assert!(token.has_scope_prefix("string.quoted.double"));
+        assert!(token.has_scope_prefix("source"));
+        assert!(!token.has_scope_prefix("string.quoted.single"));
+        assert!(!token.has_scope_prefix("str"));
+        assert!(!token.has_scope_prefix("quoted"));
+
+        let token = Token {
+            range: 0..1,
+            scopes: Vec::new(),
+        };
+        assert_eq!(token.innermost_scope(), None);
+        assert!(!token.has_scope_prefix("string"));
+    }
+
+    #[test]
+    fn coalesces_tokens_with_the_same_scopes() {
+        let tmlang = r#"{
+          "scopeName": "source.runs",
+          "patterns": [{ "match": "(a)(b)(c)", "captures": { "1": { "name": "letter" }, "2": { "name": "letter" }, "3": { "name": "letter" } } }]
+        }"#;
+        let line = "xabcabcy";
+        let syntax_set = compile(tmlang);
+        let tokens = root(&syntax_set).tokenize_line(line, &mut TokenizerState::default());
+        assert_eq!(tokens.len(), 8);
+
+        let coalesced: Vec<_> = Token::coalesce(tokens)
+            .into_iter()
+            .map(|token| (token.text(line), inner_scopes(&token)))
+            .collect();
+        assert_eq!(
+            coalesced,
+            [
+                ("x", "".to_string()),
+                ("abcabc", "letter".to_string()),
+                ("y", "".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn nests_begin_end_rules() {
+        assert_eq!(
+            tokenize(PARENS_TMLANG, "x (a (b)) c"),
+            vec![
+                ("x ", "".to_string()),
+                ("(", "expression.group".to_string()),
+                ("a", "expression.group keyword.letter".to_string()),
+                (" ", "expression.group".to_string()),
+                ("(", "expression.group expression.group".to_string()),
+                (
+                    "b",
+                    "expression.group expression.group keyword.letter".to_string()
+                ),
+                (")", "expression.group expression.group".to_string()),
+                (")", "expression.group".to_string()),
+                (" ", "".to_string()),
+                ("c", "keyword.letter".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_begin_end_regions_without_patterns() {
+        let tmlang = r#"{
+          "scopeName": "source.regions",
+          "patterns": [
+            { "begin": "<", "end": ">", "name": "empty", "patterns": [] },
+            { "begin": "\\[", "end": "\\]", "contentName": "absent" },
+            { "begin": "\\{", "end": "\\}", "name": "noop", "patterns": [{}, { "patterns": [] }] },
+            { "patterns": [] },
+            { "match": "a", "name": "letter" }
+          ]
+        }"#;
+        assert_eq!(
+            tokenize(tmlang, "<a>[a]{a}a"),
+            vec![
+                ("<", "empty".to_string()),
+                ("a", "empty".to_string()),
+                (">", "empty".to_string()),
+                ("[", "".to_string()),
+                ("a", "absent".to_string()),
+                ("]", "".to_string()),
+                ("{", "noop".to_string()),
+                ("a", "noop".to_string()),
+                ("}", "noop".to_string()),
+                ("a", "letter".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn leftmost_match_wins_over_pattern_order() {
+        let overlapping = r#"{
+          "scopeName": "source.overlapping",
+          "patterns": [
+            { "match": "bc", "name": "late" },
+            { "match": "ab", "name": "early" },
+            { "match": "a", "name": "shadowed" }
+          ]
+        }"#;
+        assert_eq!(
+            tokenize(overlapping, "abc"),
+            vec![("ab", "early".to_string()), ("c", "".to_string())]
+        );
+        assert_eq!(
+            tokenize(overlapping, "xbc"),
+            vec![("x", "".to_string()), ("bc", "late".to_string())]
+        );
+    }
+
+    #[test]
+    fn unterminated_rule_scopes_rest_of_line() {
+        assert_eq!(
+            tokenize(PARENS_TMLANG, "(xy"),
+            vec![
+                ("(", "expression.group".to_string()),
+                ("xy", "expression.group".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn carries_state_between_lines() {
+        let syntax_set = compile(PARENS_TMLANG);
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+
+        let lines = ["(a", "b", ")c"];
+        let scopes: Vec<Vec<String>> = lines
+            .iter()
+            .flat_map(|line| tokenizer.tokenize_line(line, &mut state))
+            .map(|token| token.scopes)
+            .collect();
+
+        let base = "source.parens".to_string();
+        let group = "expression.group".to_string();
+        let letter = "keyword.letter".to_string();
+        assert_eq!(
+            scopes,
+            vec![
+                vec![base.clone(), group.clone()],
+                vec![base.clone(), group.clone(), letter.clone()],
+                vec![base.clone(), group.clone(), letter.clone()],
+                vec![base.clone(), group.clone()],
+                vec![base.clone(), letter.clone()],
+            ]
+        );
+    }
+
+    #[test]
+    fn same_state_gives_same_tokens() {
+        let syntax_set = compile(PARENS_TMLANG);
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+        tokenizer.tokenize_line("((a", &mut state);
+
+        let mut first = state.clone();
+        let mut second = state.clone();
+        assert_eq!(
+            tokenizer.tokenize_line("b) c", &mut first),
+            tokenizer.tokenize_line("b) c", &mut second)
+        );
+        assert_eq!(first.stack.len(), second.stack.len());
+    }
+
+    #[test]
+    fn empty_matches_do_not_loop() {
+        let empty_match = r#"{
+          "scopeName": "source.empty",
+          "patterns": [{ "match": "x*", "name": "empty" }]
+        }"#;
+        assert_eq!(tokenize(empty_match, "ab"), vec![("ab", "".to_string())]);
+
+        let empty_begin_end = r#"{
+          "scopeName": "source.empty",
+          "patterns": [{ "begin": "(?=y)", "end": "(?=y)", "name": "lookahead" }]
+        }"#;
+        assert_eq!(
+            tokenize(empty_begin_end, "yy"),
+            vec![("yy", "lookahead".to_string())]
+        );
+
+        let empty_recursive_begin = r#"{
+          "scopeName": "source.empty",
+          "patterns": [
+            { "begin": "(?=y)", "end": "z", "name": "lookahead", "patterns": [{ "include": "$self" }] }
+          ]
+        }"#;
+        assert_eq!(
+            tokenize(empty_recursive_begin, "yy"),
+            vec![("yy", "lookahead".to_string())]
+        );
+    }
+
+    #[test]
+    fn anchors_only_match_where_the_last_begin_ended() {
+        let anchored = r#"{
+          "scopeName": "source.anchored",
+          "patterns": [{
+            "begin": "-",
+            "end": "$",
+            "name": "item",
+            "patterns": [{ "match": "\\Gx", "name": "first" }]
+          }]
+        }"#;
+        assert_eq!(
+            tokenize(anchored, "-xx"),
+            vec![
+                ("-", "item".to_string()),
+                ("x", "item first".to_string()),
+                ("x", "item".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn substitutes_begin_captures_into_end() {
+        let quoted = r#"{
+          "scopeName": "source.quoted",
+          "patterns": [{ "begin": "q(.)", "end": "\\1", "name": "quoted" }]
+        }"#;
+        assert_eq!(
+            tokenize(quoted, "q.ab.c"),
+            vec![
+                ("q.", "quoted".to_string()),
+                ("ab", "quoted".to_string()),
+                (".", "quoted".to_string()),
+                ("c", "".to_string()),
+            ]
+        );
+
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.heredoc",
+              "patterns": [{ "begin": "<<(?<tag>\\w+)$", "end": "^\\k<tag>$", "name": "heredoc" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+        let lines = ["x <<EOF", "FOO", "EOF", "x"];
+        let scopes: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                tokenizer
+                    .tokenize_line(line, &mut state)
+                    .pop()
+                    .unwrap()
+                    .scopes
+            })
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                vec!["source.heredoc", "heredoc"],
+                vec!["source.heredoc", "heredoc"],
+                vec!["source.heredoc", "heredoc"],
+                vec!["source.heredoc"],
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_begin_captures_of_every_open_rule_across_lines() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.heredoc",
+              "patterns": [{
+                "begin": "<<(\\w+)",
+                "end": "^\\1$",
+                "name": "heredoc",
+                "patterns": [{ "include": "$self" }]
+              }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+        // the outer delimiter doesn't end the inner heredoc, only its own does
+        let depths: Vec<_> = ["<<A", "<<B", "A", "B", "A", "A"]
+            .iter()
+            .map(|line| {
+                tokenizer.tokenize_line(line, &mut state);
+                state.stack.len()
+            })
+            .collect();
+        assert_eq!(depths, [2, 3, 3, 2, 1, 1]);
+    }
+
+    #[test]
+    fn caches_substituted_end_patterns() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.quoted",
+              "patterns": [{ "begin": "q(.)", "end": "\\1", "name": "quoted" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let cached = |state: &TokenizerState| -> Vec<String> {
+            let entries = &state.end_regexes.entries;
+            entries.iter().map(|(key, _)| key.2.clone()).collect()
+        };
+
+        let mut state = TokenizerState::default();
+        tokenizer.tokenize_line("q'a' q'b' q.c.", &mut state);
+        assert_eq!(cached(&state), vec!["\\.", "'"]);
+
+        let mut state = TokenizerState::with_regex_cache_capacity(1);
+        tokenizer.tokenize_line("q'a' q.c.", &mut state);
+        assert_eq!(cached(&state), vec!["\\."]);
+
+        let mut state = TokenizerState::with_regex_cache_capacity(0);
+        tokenizer.tokenize_line("q'a'", &mut state);
+        assert!(cached(&state).is_empty());
+    }
+
+    #[test]
+    fn states_compare_by_their_rule_stack() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.quoted",
+              "patterns": [{ "begin": "q(.)", "end": "\\1", "name": "quoted" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let state_after = |lines: &[&str]| {
+            let mut state = TokenizerState::default();
+            for line in lines {
+                tokenizer.tokenize_line(line, &mut state);
+            }
+            state
+        };
+        let hash = |state: &TokenizerState| {
+            let mut hasher = std::hash::DefaultHasher::new();
+            state.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // different text and cached end patterns, but the same open rule
+        let a = state_after(&["q'", "x"]);
+        let b = state_after(&["q.a. q'", "yy"]);
+        assert_ne!(a.end_regexes.entries.len(), b.end_regexes.entries.len());
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        assert_ne!(a, state_after(&["q."]));
+        assert_ne!(a, state_after(&["q'x'"]));
+    }
+
+    #[test]
+    fn while_rules_continue_while_lines_match() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.quote",
+              "patterns": [{
+                "begin": "^>",
+                "while": "^>",
+                "name": "quote",
+                "patterns": [{ "match": "b", "name": "bold" }]
+              }, { "match": "b", "name": "outer" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+
+        let lines: Vec<Vec<_>> = ["> a", "> b", "b"]
+            .into_iter()
+            .map(|line| {
+                tokenizer
+                    .tokenize_line(line, &mut state)
+                    .into_iter()
+                    .map(|token| (token.text(line), inner_scopes(&token)))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                vec![(">", "quote".to_string()), (" a", "quote".to_string())],
+                vec![
+                    (">", "quote".to_string()),
+                    (" ", "quote".to_string()),
+                    ("b", "quote bold".to_string()),
+                ],
+                vec![("b", "outer".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn anchors_while_rules_where_the_outer_while_ended() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.quote",
+              "patterns": [{
+                "begin": "(^|\\G)\\s*>",
+                "while": "(^|\\G)\\s*>",
+                "name": "quote",
+                "patterns": [{ "include": "$self" }]
+              }, { "match": "b", "name": "word" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+
+        // the inner quote's `\G` only matches where the outer quote's `>` ended;
+        // once it fails, the rest of the line goes to the outer quote
+        let lines: Vec<Vec<_>> = ["> > b", "> > b", "> b", "b"]
+            .into_iter()
+            .map(|line| {
+                tokenizer
+                    .tokenize_line(line, &mut state)
+                    .into_iter()
+                    .map(|token| (token.text(line), inner_scopes(&token)))
+                    .collect()
+            })
+            .collect();
+        let nested = vec![
+            (">", "quote".to_string()),
+            (" >", "quote quote".to_string()),
+            (" ", "quote quote".to_string()),
+            ("b", "quote quote word".to_string()),
+        ];
+        assert_eq!(
+            lines,
+            vec![
+                nested.clone(),
+                nested,
+                vec![
+                    (">", "quote".to_string()),
+                    (" ", "quote".to_string()),
+                    ("b", "quote word".to_string()),
+                ],
+                vec![("b", "word".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn anchors_while_rules_at_line_start_after_begin_ends_the_line() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.quote",
+              "patterns": [{
+                "begin": ">$",
+                "while": "\\G\\s*>",
+                "name": "quote",
+                "patterns": [{ "match": "b", "name": "word" }]
+              }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+
+        // like vscode-textmate, `\G` is only allowed at the start of the line
+        // when the begin match reached the end of its line
+        let scopes: Vec<Vec<_>> = [">", " > b", "b"]
+            .into_iter()
+            .map(|line| {
+                tokenizer
+                    .tokenize_line(line, &mut state)
+                    .iter()
+                    .map(inner_scopes)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                vec!["quote"],
+                vec!["quote", "quote", "quote word"],
+                vec![""],
+            ]
+        );
+    }
+
+    #[test]
+    fn end_pattern_can_be_applied_last() {
+        let grammar = |apply_end_pattern_last: bool| {
+            format!(
+                r#"{{
+                  "scopeName": "source.parens",
+                  "patterns": [{{
+                    "begin": "\\(",
+                    "end": "\\)",
+                    "name": "group",
+                    "applyEndPatternLast": {apply_end_pattern_last},
+                    "patterns": [{{ "match": "\\)\\)", "name": "double" }}]
+                  }}]
+                }}"#
+            )
+        };
+
+        assert_eq!(
+            tokenize(&grammar(false), "())"),
+            vec![
+                ("(", "group".to_string()),
+                (")", "group".to_string()),
+                (")", "".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize(&grammar(true), "())"),
+            vec![
+                ("(", "group".to_string()),
+                ("))", "group double".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn include_cycles_do_not_loop() {
+        let cyclic = r##"{
+          "scopeName": "source.cyclic",
+          "patterns": [{ "include": "#a" }],
+          "repository": {
+            "a": { "patterns": [{ "include": "#b" }, { "match": "x", "name": "x" }] },
+            "b": { "patterns": [{ "include": "#a" }, { "include": "#b" }, { "match": "y", "name": "y" }] }
+          }
+        }"##;
+        assert_eq!(
+            tokenize(cyclic, "xyz"),
+            vec![
+                ("x", "x".to_string()),
+                ("y", "y".to_string()),
+                ("z", "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn begin_without_end_closes_at_end_of_line() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.unclosed",
+              "patterns": [{ "begin": "<", "name": "tag" }]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+
+        let tag = tokenizer.tokenize_line("a<b", &mut state);
+        assert_eq!(tag.last().unwrap().scopes, ["source.unclosed", "tag"]);
+        let next = tokenizer.tokenize_line("c", &mut state);
+        assert_eq!(next.last().unwrap().scopes, ["source.unclosed"]);
+    }
+
+    #[test]
+    fn content_name_only_scopes_the_inside() {
+        let quoted = r#"{
+          "scopeName": "source.quoted",
+          "patterns": [{ "begin": "\"", "end": "\"", "name": "string", "contentName": "content" }]
+        }"#;
+        assert_eq!(
+            tokenize(quoted, "\"ab\"c"),
+            vec![
+                ("\"", "string".to_string()),
+                ("ab", "string content".to_string()),
+                ("\"", "string".to_string()),
+                ("c", "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn begin_without_patterns_only_looks_for_the_end() {
+        let syntax_set = compile(
+            r#"{
+              "scopeName": "source.strings",
+              "patterns": [
+                {
+                  "begin": "'",
+                  "end": "'",
+                  "name": "string",
+                  "contentName": "content",
+                  "captures": { "0": { "name": "delimiter" } }
+                },
+                { "match": "a", "name": "letter" }
+              ]
+            }"#,
+        );
+        let tokenizer = root(&syntax_set);
+        let mut state = TokenizerState::default();
+        let mut tokenize = |line: &'static str| -> Vec<(&str, String)> {
+            let tokens = tokenizer.tokenize_line(line, &mut state);
+            let text = |token: &Token| token.text(line);
+            tokens.iter().map(|t| (text(t), inner_scopes(t))).collect()
+        };
+
+        assert_eq!(
+            tokenize("a'a"),
+            vec![
+                ("a", "letter".to_string()),
+                ("'", "string delimiter".to_string()),
+                ("a", "string content".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("a'a"),
+            vec![
+                ("a", "string content".to_string()),
+                ("'", "string delimiter".to_string()),
+                ("a", "letter".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_capture_groups() {
+        let def = r#"{
+          "scopeName": "source.def",
+          "patterns": [{
+            "match": "(def) ((\\w)\\w*)",
+            "name": "definition",
+            "captures": {
+              "1": { "name": "keyword" },
+              "2": { "name": "entity" },
+              "3": { "name": "first" }
+            }
+          }]
+        }"#;
+        assert_eq!(
+            tokenize(def, "def foo"),
+            vec![
+                ("def", "definition keyword".to_string()),
+                (" ", "definition".to_string()),
+                ("f", "definition entity first".to_string()),
+                ("oo", "definition entity".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_indexed_capture_groups() {
+        // captures as an array, as some grammars (e.g. jinja) write them; the
+        // index is the group
+        let def = r#"{
+          "scopeName": "source.def",
+          "patterns": [{
+            "begin": "(def) (\\w+)",
+            "end": "(;)",
+            "beginCaptures": [{}, { "name": "keyword" }, { "name": "entity" }],
+            "endCaptures": [{ "name": "end" }]
+          }]
+        }"#;
+        assert_eq!(
+            tokenize(def, "def foo;"),
+            vec![
+                ("def", "keyword".to_string()),
+                (" ", "".to_string()),
+                ("foo", "entity".to_string()),
+                (";", "end".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_begin_and_end_captures() {
+        // `captures` applies to both begin and end unless they have their own
+        let quoted = r#"{
+          "scopeName": "source.quoted",
+          "patterns": [{
+            "begin": "(\")",
+            "end": "(\")",
+            "name": "string",
+            "captures": { "1": { "name": "quote" } },
+            "endCaptures": { "1": { "name": "end" } }
+          }]
+        }"#;
+        assert_eq!(
+            tokenize(quoted, "\"a\""),
+            vec![
+                ("\"", "string quote".to_string()),
+                ("a", "string".to_string()),
+                ("\"", "string end".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_captures_with_their_patterns() {
+        let assignment = r##"{
+          "scopeName": "source.assignment",
+          "patterns": [{
+            "match": "(\\w+)=(.*)",
+            "captures": {
+              "1": { "name": "variable" },
+              "2": { "name": "value", "patterns": [{ "include": "#number" }] }
+            }
+          }],
+          "repository": {
+            "number": {
+              "match": "(-)?\\d+",
+              "name": "number",
+              "captures": { "1": { "name": "sign" } }
+            }
+          }
+        }"##;
+        assert_eq!(
+            tokenize(assignment, "x=-1 2"),
+            vec![
+                ("x", "variable".to_string()),
+                ("=", "".to_string()),
+                ("-", "value number sign".to_string()),
+                ("1", "value number".to_string()),
+                (" ", "value".to_string()),
+                ("2", "value number".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn captures_including_their_own_rule_terminate() {
+        let recursive = r#"{
+          "scopeName": "source.recursive",
+          "patterns": [{
+            "match": "(a)",
+            "captures": { "1": { "name": "a", "patterns": [{ "include": "$self" }] } }
+          }]
+        }"#;
+        let tokens = tokenize(recursive, "a");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].0, "a");
+    }
+
+    #[test]
+    fn base_is_the_grammar_being_tokenized() {
+        // like HTML embedding CSS embedding JS, where JS includes $base
+        let syntax_set = compile_all(&[
+            r#"{
+              "scopeName": "text.page",
+              "patterns": [
+                { "match": "<b>", "name": "tag" },
+                { "begin": "<style>", "end": "</style>", "name": "style", "patterns": [{ "include": "source.style" }] }
+              ]
+            }"#,
+            r#"{
+              "scopeName": "source.style",
+              "patterns": [
+                { "match": "red", "name": "color" },
+                { "begin": "js\\(", "end": "\\)", "name": "script", "patterns": [{ "include": "source.script" }] }
+              ]
+            }"#,
+            r#"{
+              "scopeName": "source.script",
+              "patterns": [
+                { "match": "\\d", "name": "number" },
+                { "begin": "\\{", "end": "\\}", "name": "block", "patterns": [{ "include": "$base" }] }
+              ]
+            }"#,
+        ]);
+        let tokenize = |syntax_idx, line| -> Vec<_> {
+            Tokenizer::new(&syntax_set, SyntaxId::from_idx(syntax_idx))
+                .tokenize_line(line, &mut TokenizerState::default())
+                .into_iter()
+                .map(|token| (token.text(line), inner_scopes(&token)))
+                .collect()
+        };
+
+        // in the page, $base is the page, however deep the script is embedded
+        let line = "<style>js({<b>red}1)</style>";
+        assert_eq!(
+            tokenize(0, line),
+            [
+                ("<style>", "style".to_string()),
+                ("js(", "style script".to_string()),
+                ("{", "style script block".to_string()),
+                ("<b>", "style script block tag".to_string()),
+                ("red", "style script block".to_string()),
+                ("}", "style script block".to_string()),
+                ("1", "style script number".to_string()),
+                (")", "style script".to_string()),
+                ("</style>", "style".to_string()),
+            ]
+        );
+
+        // on its own, the style sheet is the base
+        let line = "js({<b>red})";
+        assert_eq!(
+            tokenize(1, line),
+            [
+                ("js(", "script".to_string()),
+                ("{", "script block".to_string()),
+                ("<b>", "script block".to_string()),
+                ("red", "script block color".to_string()),
+                ("}", "script block".to_string()),
+                (")", "script".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn injections_match_in_scopes_their_selector_matches() {
+        let syntax_set = compile_all(&[
+            r##"{
+              "scopeName": "source.outer",
+              "patterns": [
+                { "begin": "//", "end": "$", "name": "comment.line" },
+                { "begin": "<", "end": ">", "name": "embedded", "patterns": [{ "include": "source.inner" }] }
+              ],
+              "injections": {
+                "comment": { "patterns": [{ "match": "TODO", "name": "keyword.todo" }] },
+                "source.outer embedded -comment": { "patterns": [{ "match": "\\d", "name": "number" }] }
+              }
+            }"##,
+            r##"{
+              "scopeName": "source.inner",
+              "patterns": [{ "begin": "#", "end": "(?=>)", "name": "comment.block" }]
+            }"##,
+        ]);
+
+        let line = "TODO 1<1#TODO 1>//TODO";
+        let tokens: Vec<_> = root(&syntax_set)
+            .tokenize_line(line, &mut TokenizerState::default())
+            .into_iter()
+            .map(|token| (token.text(line), inner_scopes(&token)))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                ("TODO 1", "".to_string()),
+                ("<", "embedded".to_string()),
+                ("1", "embedded number".to_string()),
+                ("#", "embedded comment.block".to_string()),
+                ("TODO", "embedded comment.block keyword.todo".to_string()),
+                (" 1", "embedded comment.block".to_string()),
+                (">", "embedded".to_string()),
+                ("//", "comment.line".to_string()),
+                ("TODO", "comment.line keyword.todo".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn injection_priority_decides_ties() {
+        let contested = |selector: &str| {
+            let tmlang = format!(
+                r#"{{
+                  "scopeName": "source.contested",
+                  "patterns": [{{ "match": "a", "name": "own" }}],
+                  "injections": {{
+                    "{selector}": {{ "patterns": [{{ "match": "a", "name": "injected" }}] }}
+                  }}
+                }}"#
+            );
+            tokenize(&tmlang, "a")[0].1.clone()
+        };
+
+        assert_eq!(contested("L:source.contested"), "injected");
+        assert_eq!(contested("source.contested"), "own");
+        assert_eq!(contested("R:source.contested"), "own");
+
+        // among injections, R: ones come after the rest, whatever the order
+        let injections = r#"{
+          "scopeName": "source.injections",
+          "patterns": [],
+          "injections": {
+            "R:source.injections": { "patterns": [{ "match": "b", "name": "right" }] },
+            "source.injections": { "patterns": [{ "match": "b", "name": "normal" }] }
+          }
+        }"#;
+        assert_eq!(tokenize(injections, "b"), vec![("b", "normal".to_string())]);
+    }
+
+    #[test]
+    fn enters_rules_of_other_grammars() {
+        let syntax_set = compile_all(&[
+            r#"{
+              "scopeName": "source.outer",
+              "patterns": [{
+                "begin": "<",
+                "end": ">",
+                "name": "embedded",
+                "patterns": [{ "include": "source.inner" }]
+              }]
+            }"#,
+            r##"{
+              "scopeName": "source.inner",
+              "patterns": [{ "include": "#string" }],
+              "repository": {
+                "string": { "begin": "'", "end": "'", "name": "string", "patterns": [{ "include": "#escape" }] },
+                "escape": { "match": "\\\\.", "name": "escape" }
+              }
+            }"##,
+        ]);
+
+        let line = "<'\\n'>";
+        let tokens: Vec<_> = root(&syntax_set)
+            .tokenize_line(line, &mut TokenizerState::default())
+            .into_iter()
+            .map(|token| (token.text(line), inner_scopes(&token)))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                ("<", "embedded".to_string()),
+                ("'", "embedded string".to_string()),
+                ("\\n", "embedded string escape".to_string()),
+                ("'", "embedded string".to_string()),
+                (">", "embedded".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Smoke tests for the `tmhl` binary.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const GRAMMAR: &str = r#"{
+  "scopeName": "source.t",
+  "fileTypes": ["t"],
+  "patterns": [
+    { "begin": "//", "end": "\\n", "name": "comment.line.t" },
+    { "match": "\\bfn\\b", "name": "keyword.t" }
+  ]
+}"#;
+
+const THEME: &str = r##"{
+  "name": "t",
+  "colors": { "editor.foreground": "#ffffff", "editor.background": "#000000" },
+  "tokenColors": [
+    { "scope": "comment", "settings": { "foreground": "#00ff00" } },
+    { "scope": "keyword", "settings": { "foreground": "#ff0000" } }
+  ]
+}"##;
+
+const GREEN: &str = "38;2;0;255;0";
+const RED: &str = "38;2;255;0;0";
+
+fn fixtures(name: &str, input: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tmhl-{}-{name}", std::process::id()));
+    // a directory of its own, for --grammars
+    fs::create_dir_all(dir.join("grammars")).unwrap();
+    fs::write(dir.join("grammars/t.json"), GRAMMAR).unwrap();
+    fs::write(dir.join("theme.json"), THEME).unwrap();
+    fs::write(dir.join("input.t"), input).unwrap();
+    dir
+}
+
+fn run(dir: &Path, grammar_args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tmhl"))
+        .args(grammar_args)
+        .arg("--theme")
+        .arg(dir.join("theme.json"))
+        .arg(dir.join("input.t"))
+        .output()
+        .unwrap()
+}
+
+fn tmhl(dir: &Path, grammar_args: &[&str]) -> String {
+    let output = run(dir, grammar_args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn ends_comments_at_the_end_of_their_line() {
+    let dir = fixtures("comments", "fn a // note\nfn b\n");
+    let grammar = dir.join("grammars/t.json");
+    let output = tmhl(&dir, &["--grammar", grammar.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(GREEN));
+    assert!(lines[1].contains(RED));
+    assert!(!lines[1].contains(GREEN));
+}
+
+#[test]
+fn picks_grammars_by_extension() {
+    let dir = fixtures("extension", "fn a\n");
+    let grammars = dir.join("grammars");
+    let output = tmhl(&dir, &["--grammars", grammars.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.contains(RED));
+}
+
+#[test]
+fn fails_on_bad_grammars() {
+    let dir = fixtures("bad", "fn a\n");
+    let grammar = dir.join("grammars/bad.json");
+    fs::write(&grammar, r#"{ "scopeName": "#).unwrap();
+    let output = run(&dir, &["--grammar", grammar.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("failed to load grammar"), "{stderr}");
+    assert!(stderr.contains("bad.json"), "{stderr}");
+}
+
+#[test]
+fn resolves_theme_includes_relative_to_the_including_theme() {
+    let dir = fixtures("includes", "fn a\n");
+    fs::create_dir_all(dir.join("themes")).unwrap();
+    fs::write(
+        dir.join("theme.json"),
+        r#"{ "include": "./themes/base.json" }"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("themes/base.json"),
+        r#"{ "include": "./colors.json" }"#,
+    )
+    .unwrap();
+    fs::write(dir.join("themes/colors.json"), THEME).unwrap();
+    let grammar = dir.join("grammars/t.json");
+    let output = tmhl(&dir, &["--grammar", grammar.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.contains(RED));
+}